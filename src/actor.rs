@@ -1,11 +1,12 @@
 //! The actor trait
-use std::sync::mpsc::Sender;
-
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::errors::Error;
 use crate::message::Message;
+use crate::rpc::RpcReply;
+use crate::runtime::Handle;
+use crate::timer::TimerId;
 
 pub type ActorID = String;
 
@@ -16,7 +17,7 @@ pub trait Actor {
     /// Initiate node with a name and a topology
     fn init(
         &mut self,
-        tx: Sender<Message<Self::MessagePayload>>,
+        handle: Handle<Self::MessagePayload>,
         node_id: String,
         peers: Vec<String>,
     ) -> Result<(), Error>;
@@ -26,4 +27,19 @@ pub trait Actor {
         &mut self,
         request: &Message<Self::MessagePayload>,
     ) -> Result<Vec<Message<Self::MessagePayload>>, Error>;
+
+    /// Receive the outcome of a request sent with [`Handle::call`].
+    /// By default, replies are handed to `receive` and failed requests are dropped.
+    fn on_reply(&mut self, reply: RpcReply) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match reply.result {
+            Ok(_) => self.receive(&reply.decode()?),
+            Err(_) => Ok(vec![]),
+        }
+    }
+
+    /// Called when a timer scheduled with [`Handle::schedule_once`] or
+    /// [`Handle::schedule_every`] fires.
+    fn on_timer(&mut self, _timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        Ok(vec![])
+    }
 }
@@ -0,0 +1,148 @@
+//! Append-only logs, one per key, as served by the kafka workload.
+//!
+//! Offsets of a key start at 0 and leave no gap, so a replica can tell whether an entry it
+//! is given directly follows the ones it holds.
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+pub type Offset = u64;
+
+/// Logs of every key, with the offset each key was committed up to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Logs<T> {
+    logs: HashMap<String, Vec<T>>,
+    committed: BTreeMap<String, Offset>,
+}
+
+impl<T> Default for Logs<T> {
+    fn default() -> Self {
+        Self {
+            logs: HashMap::new(),
+            committed: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Logs<T> {
+    /// Append `entry` to the log of `key`, returning its offset
+    pub fn append(&mut self, key: &str, entry: T) -> Offset {
+        let log = self.logs.entry(key.to_owned()).or_default();
+        log.push(entry);
+        log.len() as Offset - 1
+    }
+
+    /// Store an entry appended elsewhere, if it directly follows the ones we hold.
+    /// Returns whether it did.
+    pub fn insert(&mut self, key: &str, offset: Offset, entry: T) -> bool {
+        let log = self.logs.entry(key.to_owned()).or_default();
+        if offset == log.len() as Offset {
+            log.push(entry);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Offset the next entry of `key` will get
+    pub fn next_offset(&self, key: &str) -> Offset {
+        self.logs.get(key).map_or(0, |log| log.len() as Offset)
+    }
+
+    /// Entries of `key` from `offset` on, with their offset
+    pub fn read_from(&self, key: &str, offset: Offset) -> Vec<(Offset, T)> {
+        let log = match self.logs.get(key) {
+            Some(log) => log,
+            None => return vec![],
+        };
+        log.iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(offset, entry)| (offset as Offset, entry.to_owned()))
+            .collect()
+    }
+
+    /// Keys which have a log, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.logs.keys()
+    }
+
+    /// Record that `key` was consumed up to `offset`. Committed offsets never go backwards.
+    pub fn commit(&mut self, key: &str, offset: Offset) {
+        let committed = self.committed.entry(key.to_owned()).or_default();
+        *committed = (*committed).max(offset);
+    }
+
+    pub fn committed(&self, key: &str) -> Option<Offset> {
+        self.committed.get(key).copied()
+    }
+
+    /// Offset committed for every key which has one
+    pub fn committed_offsets(&self) -> &BTreeMap<String, Offset> {
+        &self.committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(entries: &[&'static str]) -> Logs<&'static str> {
+        let mut logs = Logs::default();
+        for entry in entries {
+            logs.append("k", *entry);
+        }
+        logs
+    }
+
+    #[test]
+    fn appends_get_consecutive_offsets_per_key() {
+        let mut logs = logs(&["a", "b"]);
+        assert_eq!(logs.append("k", "c"), 2);
+        assert_eq!(logs.append("other", "x"), 0);
+        assert_eq!(logs.next_offset("k"), 3);
+        assert_eq!(logs.next_offset("other"), 1);
+        assert_eq!(logs.next_offset("missing"), 0);
+    }
+
+    #[test]
+    fn inserts_past_a_gap_are_rejected() {
+        let mut logs = logs(&["a"]);
+        assert!(!logs.insert("k", 2, "c"));
+        assert_eq!(logs.next_offset("k"), 1);
+        assert!(logs.insert("k", 1, "b"));
+        assert!(logs.insert("k", 2, "c"));
+        assert_eq!(logs.read_from("k", 0), [(0, "a"), (1, "b"), (2, "c")]);
+    }
+
+    #[test]
+    fn inserting_an_entry_held_already_changes_nothing() {
+        let mut logs = logs(&["a", "b"]);
+        assert!(!logs.insert("k", 1, "b"));
+        assert!(!logs.insert("k", 0, "a"));
+        assert_eq!(logs.read_from("k", 0), [(0, "a"), (1, "b")]);
+        assert_eq!(logs.next_offset("k"), 2);
+    }
+
+    #[test]
+    fn reads_start_at_the_offset_asked_for() {
+        let logs = logs(&["a", "b", "c"]);
+        assert_eq!(logs.read_from("k", 1), [(1, "b"), (2, "c")]);
+        assert_eq!(logs.read_from("k", 2), [(2, "c")]);
+        assert!(logs.read_from("k", 3).is_empty());
+        assert!(logs.read_from("k", 100).is_empty());
+        assert!(logs.read_from("missing", 0).is_empty());
+    }
+
+    #[test]
+    fn commits_never_move_backwards() {
+        let mut logs = logs(&["a", "b", "c"]);
+        assert_eq!(logs.committed("k"), None);
+        logs.commit("k", 2);
+        logs.commit("k", 1);
+        assert_eq!(logs.committed("k"), Some(2));
+        logs.commit("other", 0);
+        let expected = BTreeMap::from([("k".to_owned(), 2), ("other".to_owned(), 0)]);
+        assert_eq!(logs.committed_offsets(), &expected);
+    }
+}
@@ -0,0 +1,470 @@
+//! Async runtime built on tokio, enabled with the `async` feature.
+//!
+//! Every incoming message is handled in its own task, so handlers can `await`
+//! replies from other nodes and timers without blocking the rest of the node.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    actor::ActorID,
+    errors::{Error, ErrorBody},
+    message::{Message, MessageID, MessageIdAllocator},
+    rpc::{self, RpcReply},
+    runtime::{InitAckMsg, InitMsg, MalformedPolicy},
+};
+
+/// The async counterpart of [`Actor`](crate::actor::Actor).
+/// Handlers take `&self` as they may run concurrently; use interior mutability for state.
+pub trait AsyncActor: Send + Sync + 'static {
+    type MessagePayload: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Initiate node with a name and a topology
+    fn init(
+        &self,
+        ctx: Context,
+        node_id: String,
+        peers: Vec<String>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Receive a request. Will answer with a Vec of messages.
+    fn receive(
+        &self,
+        ctx: Context,
+        request: Message<Self::MessagePayload>,
+    ) -> impl Future<Output = Result<Vec<Message<Self::MessagePayload>>, Error>> + Send;
+}
+
+type ReplySlots = Arc<Mutex<HashMap<MessageID, oneshot::Sender<Message<Value>>>>>;
+
+/// Handle given to every handler to talk to the rest of the cluster
+#[derive(Clone)]
+pub struct Context {
+    node_id: ActorID,
+    msg_ids: MessageIdAllocator,
+    out: mpsc::UnboundedSender<String>,
+    replies: ReplySlots,
+}
+
+impl Context {
+    /// Name of the node we are running as
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Allocate a msg_id for a request we build ourselves
+    pub fn next_msg_id(&self) -> MessageID {
+        self.msg_ids.next()
+    }
+
+    /// Send a message without expecting a reply
+    pub fn send<U: Serialize>(&self, msg: Message<U>) -> Result<(), Error> {
+        self.out
+            .send(msg.serialize())
+            .map_err(|_| Error::TemporarilyUnavailable)
+    }
+
+    /// Send a request to `dest` and wait for its reply, giving up after [`rpc::DEFAULT_TIMEOUT`]
+    pub async fn call<U: Serialize>(&self, dest: ActorID, body: &U) -> RpcReply {
+        self.call_timeout(dest, body, rpc::DEFAULT_TIMEOUT).await
+    }
+
+    /// Send a request to `dest` and wait at most `timeout` for its reply.
+    /// A fresh `msg_id` is filled into the body.
+    pub async fn call_timeout<U: Serialize>(
+        &self,
+        dest: ActorID,
+        body: &U,
+        timeout: Duration,
+    ) -> RpcReply {
+        let msg_id = self.msg_ids.next();
+        let result = self.request(msg_id, dest.to_owned(), body, timeout).await;
+        RpcReply {
+            request_id: msg_id,
+            dest,
+            result,
+        }
+    }
+
+    async fn request<U: Serialize>(
+        &self,
+        msg_id: MessageID,
+        dest: ActorID,
+        body: &U,
+        timeout: Duration,
+    ) -> Result<Message<Value>, Error> {
+        let mut body = serde_json::to_value(body).map_err(|_| Error::MalformedRequest)?;
+        body.as_object_mut()
+            .ok_or(Error::MalformedRequest)?
+            .insert("msg_id".to_owned(), msg_id.into());
+
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().unwrap().insert(msg_id, tx);
+        self.send(Message {
+            src: self.node_id.to_owned(),
+            dest,
+            body,
+        })?;
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => rpc::reply_result(reply),
+            Ok(Err(_)) => Err(Error::Crash),
+            Err(_) => {
+                self.replies.lock().unwrap().remove(&msg_id);
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Wait for `duration` to elapse
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    /// Run a future in the background, e.g. a periodic gossip loop
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        tokio::spawn(future);
+    }
+
+    /// Route a reply to the request waiting on it. Gives the message back if nobody is.
+    fn deliver(&self, msg: Message<Value>) -> Option<Message<Value>> {
+        let slot = msg
+            .in_reply_to()
+            .and_then(|id| self.replies.lock().unwrap().remove(&id));
+        match slot {
+            Some(tx) => {
+                let _ = tx.send(msg);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+}
+
+pub struct AsyncRuntime<T: AsyncActor + Default> {
+    node: Arc<T>,
+    malformed_policy: MalformedPolicy,
+}
+
+impl<T: AsyncActor + Default> Default for AsyncRuntime<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: AsyncActor + Default> AsyncRuntime<T> {
+    pub fn new() -> Self {
+        Self {
+            node: Default::default(),
+            malformed_policy: Default::default(),
+        }
+    }
+
+    /// Choose what happens to messages which cannot be parsed. Defaults to `MalformedPolicy::Reply`.
+    pub fn with_malformed_policy(mut self, policy: MalformedPolicy) -> Self {
+        self.malformed_policy = policy;
+        self
+    }
+
+    /// Run the node on a multi-threaded tokio runtime until stdin is closed
+    pub fn start(&mut self) {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("could not build tokio runtime")
+            .block_on(self.run());
+    }
+
+    /// Run the node on the current tokio runtime until stdin is closed
+    pub async fn run(&mut self) {
+        let stdin = BufReader::new(tokio::io::stdin());
+        self.serve(stdin, tokio::io::stdout()).await
+    }
+
+    /// Run the node over `input` and `output` instead of stdin and stdout, until `input` is
+    /// closed or cannot be read
+    async fn serve<R, W>(&mut self, input: R, mut output: W)
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (out, mut out_rx) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(line) = out_rx.recv().await {
+                let written = output.write_all(line.as_bytes()).await;
+                let written = match written {
+                    Ok(_) => output.write_all(b"\n").await,
+                    e => e,
+                };
+                if let Err(e) = written.and(output.flush().await) {
+                    eprintln!("could not write to stdout: {}", e);
+                    break;
+                }
+            }
+        });
+
+        let mut lines = input.lines();
+
+        // read an init message
+        let buffer = match lines.next_line().await {
+            Ok(Some(buffer)) => buffer,
+            Ok(None) => {
+                eprintln!("stdin closed before an init message");
+                return;
+            }
+            Err(e) => {
+                eprintln!("could not read stdin: {}", e);
+                return;
+            }
+        };
+        let init_msg: Message<InitMsg> =
+            Message::deserialize(&buffer).expect("expected a valid init message");
+        let ctx = Context {
+            node_id: init_msg.body.node_id.to_owned(),
+            msg_ids: Default::default(),
+            out,
+            replies: Default::default(),
+        };
+
+        // initialize node
+        self.node
+            .init(
+                ctx.clone(),
+                init_msg.body.node_id.to_owned(),
+                init_msg.body.node_ids.to_owned(),
+            )
+            .await
+            .expect("initialization to not error");
+
+        // ack
+        let ack = InitAckMsg {
+            message_type: "init_ok".to_owned(),
+            in_reply_to: init_msg.body.msg_id,
+        };
+        ctx.send(Message::new_reply_to(&init_msg, ack))
+            .expect("writer to be running");
+
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("could not read stdin: {}", e);
+                    break;
+                }
+            };
+            let msg: Message<Value> = match Message::deserialize(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.malformed_policy.reject(&line, &e);
+                    continue;
+                }
+            };
+            let Some(msg) = ctx.deliver(msg) else {
+                continue;
+            };
+
+            let request_id = msg.msg_id();
+            let (src, dest) = (msg.src.to_owned(), msg.dest.to_owned());
+            let msg = match msg.decode() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if let (true, Some(request_id)) =
+                        (self.malformed_policy.reject(&line, &e), request_id)
+                    {
+                        let _ = ctx.send(Message {
+                            src: dest,
+                            dest: src,
+                            body: ErrorBody::new(&e, request_id),
+                        });
+                    }
+                    continue;
+                }
+            };
+            let node = self.node.clone();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let (src, dest) = (msg.src.to_owned(), msg.dest.to_owned());
+                match node.receive(ctx.clone(), msg).await {
+                    Ok(responses) => {
+                        for resp in responses {
+                            let _ = ctx.send(resp);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("errored while handling message: {:?}", e);
+                        // answer with a definite error rather than letting the request time out
+                        if let Some(request_id) = request_id {
+                            let _ = ctx.send(Message {
+                                src: dest,
+                                dest: src,
+                                body: ErrorBody::new(&e, request_id),
+                            });
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Body;
+    use serde::Deserialize;
+    use serde_json::json;
+    use tokio::io::{DuplexStream, Lines};
+
+    /// Forwards every request to another node, and answers with its reply
+    #[derive(Default)]
+    struct Forwarder;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Forward { to: ActorID, timeout_ms: u64 },
+        ForwardOk { reply: Value },
+        Ping,
+    }
+
+    impl AsyncActor for Forwarder {
+        type MessagePayload = Body<Payload>;
+
+        async fn init(
+            &self,
+            _ctx: Context,
+            _node_id: String,
+            _peers: Vec<String>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn receive(
+            &self,
+            ctx: Context,
+            request: Message<Self::MessagePayload>,
+        ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+            match &request.body.payload {
+                Payload::Forward { to, timeout_ms } => {
+                    let timeout = Duration::from_millis(*timeout_ms);
+                    let reply = ctx
+                        .call_timeout(to.to_owned(), &Body::new(Payload::Ping), timeout)
+                        .await
+                        .result?;
+                    Ok(vec![request.reply(Payload::ForwardOk { reply: reply.body })])
+                }
+                Payload::ForwardOk { .. } | Payload::Ping => Ok(vec![]),
+            }
+        }
+    }
+
+    /// Both ends of a node under test: what it reads, and what it writes
+    struct Wire {
+        input: DuplexStream,
+        output: Lines<BufReader<DuplexStream>>,
+    }
+
+    impl Wire {
+        async fn send(&mut self, message: Value) {
+            let line = format!("{}\n", message);
+            self.input.write_all(line.as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            let line = self.output.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    /// Start a node, and initialize it as n1
+    async fn start() -> Wire {
+        let (input, node_input) = tokio::io::duplex(4096);
+        let (node_output, output) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut runtime = AsyncRuntime::<Forwarder>::new();
+            runtime.serve(BufReader::new(node_input), node_output).await
+        });
+        let mut wire = Wire {
+            input,
+            output: BufReader::new(output).lines(),
+        };
+        let init = json!({"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]});
+        wire.send(json!({"src": "c1", "dest": "n1", "body": init}))
+            .await;
+        assert_eq!(wire.recv().await["body"]["type"], "init_ok");
+        wire
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn requests_await_the_replies_of_other_nodes() {
+        block_on(async {
+            let mut wire = start().await;
+            let forward = json!({"type": "forward", "msg_id": 2, "to": "n2", "timeout_ms": 1000});
+            wire.send(json!({"src": "c1", "dest": "n1", "body": forward}))
+                .await;
+
+            let ping = wire.recv().await;
+            assert_eq!(ping["dest"], "n2");
+            assert_eq!(ping["body"]["type"], "ping");
+            let in_reply_to = ping["body"]["msg_id"].to_owned();
+            let pong = json!({"type": "pong", "in_reply_to": in_reply_to});
+            wire.send(json!({"src": "n2", "dest": "n1", "body": pong}))
+                .await;
+
+            let reply = wire.recv().await;
+            assert_eq!(reply["dest"], "c1");
+            assert_eq!(reply["body"]["type"], "forward_ok");
+            assert_eq!(reply["body"]["in_reply_to"], 2);
+            assert_eq!(reply["body"]["reply"]["type"], "pong");
+        })
+    }
+
+    #[test]
+    fn requests_left_unanswered_time_out() {
+        block_on(async {
+            let mut wire = start().await;
+            let forward = json!({"type": "forward", "msg_id": 2, "to": "n2", "timeout_ms": 50});
+            wire.send(json!({"src": "c1", "dest": "n1", "body": forward}))
+                .await;
+            assert_eq!(wire.recv().await["body"]["type"], "ping");
+
+            // the timeout is passed on to the client as a definite error
+            let reply = wire.recv().await;
+            assert_eq!(reply["body"]["type"], "error");
+            assert_eq!(reply["body"]["code"], 0);
+            assert_eq!(reply["body"]["in_reply_to"], 2);
+        })
+    }
+
+    #[test]
+    fn closed_input_stops_the_node() {
+        block_on(async {
+            let (input, node_input) = tokio::io::duplex(64);
+            drop(input);
+            let mut runtime = AsyncRuntime::<Forwarder>::new();
+            runtime
+                .serve(BufReader::new(node_input), tokio::io::sink())
+                .await;
+        })
+    }
+}
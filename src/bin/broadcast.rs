@@ -1,31 +1,31 @@
 use maelstrom::{
     actor::{Actor, ActorID},
+    broadcast::{Batch, BroadcastConfig, ItemID, ReliableBroadcast},
+    clock::VersionVector,
     errors::Error,
-    message::{Message, MessageID},
-    runtime::Runtime,
+    message::{Body, Message},
+    runtime::{Handle, Runtime},
+    timer::TimerId,
+    topology::Overlay,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::mpsc::Sender,
-    thread,
-    time::Duration,
-};
+use std::collections::{BTreeMap, HashMap};
 
 fn main() {
     let mut runtime = Runtime::<BroadcastActor>::new();
     runtime.start();
 }
 
-type UniqueMessageID = (ActorID, MessageID);
-
 #[derive(Default)]
 struct BroadcastActor {
     node_id: Option<ActorID>,
-    peers: Vec<ActorID>,
-    messages: Vec<(UniqueMessageID, serde_json::value::Value)>,
-    known: HashMap<ActorID, HashSet<UniqueMessageID>>,
+    /// Messages delivered so far, by their JSON form: Maelstrom never broadcasts one twice
+    messages: BTreeMap<String, Value>,
+    broadcast: Option<ReliableBroadcast<Value, Body<Payload>>>,
+    /// Overlay chosen with the `TOPOLOGY` environment variable, if any, used instead of the
+    /// topology sent by Maelstrom. An unknown overlay is ignored, with a warning.
+    overlay: Option<Overlay>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,32 +33,26 @@ struct BroadcastActor {
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Topology {
-        msg_id: MessageID,
         topology: HashMap<ActorID, Vec<ActorID>>,
     },
-    TopologyOk {
-        in_reply_to: MessageID,
-    },
+    TopologyOk,
     Broadcast {
-        msg_id: MessageID,
         message: Value,
     },
-    BroadcastOk {
-        in_reply_to: MessageID,
-    },
-    StartGossip,
+    BroadcastOk,
     Gossip {
-        payload: Vec<(UniqueMessageID, Value)>,
+        payload: Vec<(ItemID, Value)>,
+        /// Every message we delivered, and the ids of them, for a neighbour to catch up with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<(Vec<Value>, VersionVector)>,
     },
     GossipOk {
-        seen: HashSet<UniqueMessageID>,
-    },
-    Read {
-        msg_id: MessageID,
+        ids: Vec<ItemID>,
+        seen: VersionVector,
     },
+    Read,
     ReadOk {
         messages: Vec<Value>,
-        in_reply_to: MessageID,
     },
 }
 
@@ -67,35 +61,46 @@ impl BroadcastActor {
     pub fn node_id(&self) -> String {
         self.node_id.as_ref().unwrap().to_owned()
     }
+
+    fn broadcast(&mut self) -> Result<&mut ReliableBroadcast<Value, Body<Payload>>, Error> {
+        self.broadcast.as_mut().ok_or(Error::TemporarilyUnavailable)
+    }
+
+    fn add(&mut self, message: Value) {
+        self.messages.insert(message.to_string(), message);
+    }
 }
 
 impl Actor for BroadcastActor {
-    type MessagePayload = Payload;
+    type MessagePayload = Body<Payload>;
 
     fn init(
         &mut self,
-        tx: Sender<Message<Self::MessagePayload>>,
+        handle: Handle<Self::MessagePayload>,
         node_id: ActorID,
-        _node_ids: Vec<ActorID>,
+        node_ids: Vec<ActorID>,
     ) -> Result<(), Error> {
         eprintln!("Initialized node {}", node_id);
-        self.node_id = Some(node_id.clone());
-
-        thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(100));
-            match tx.send(Message {
-                src: node_id.clone(),
-                dest: node_id.clone(),
-                body: Payload::StartGossip,
-            }) {
-                Ok(_) => continue,
-                Err(e) => {
-                    eprintln!("error while sending gossip signal: {}", e);
-                    break;
+        let mut broadcast = ReliableBroadcast::new(handle, BroadcastConfig::default());
+        self.overlay = match std::env::var("TOPOLOGY") {
+            Ok(overlay) => match overlay.parse() {
+                Ok(overlay) => Some(overlay),
+                Err(_) => {
+                    eprintln!(
+                        "unknown TOPOLOGY {}, expected line, ring, grid, tree:<k>, expander:<degree> or stars:<hubs>: using the topology sent by Maelstrom",
+                        overlay
+                    );
+                    None
                 }
-            }
-        });
-
+            },
+            Err(_) => None,
+        };
+        if let Some(overlay) = &self.overlay {
+            let mut topology = overlay.build(&node_ids);
+            broadcast.set_neighbours(topology.remove(&node_id).unwrap_or_default())?;
+        }
+        self.node_id = Some(node_id);
+        self.broadcast = Some(broadcast);
         Ok(())
     }
 
@@ -103,92 +108,134 @@ impl Actor for BroadcastActor {
         &mut self,
         message: &Message<Self::MessagePayload>,
     ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
-        match &message.body {
-            Payload::Topology { msg_id, topology } => {
-                let peers = topology.get(&self.node_id()).unwrap();
-                self.peers = peers.to_owned();
-                let ack = Payload::TopologyOk {
-                    in_reply_to: *msg_id,
-                };
-                Ok(vec![Message::new_reply_to(message, ack)])
+        match &message.body.payload {
+            Payload::Topology { .. } if self.overlay.is_some() => {
+                Ok(vec![message.reply(Payload::TopologyOk)])
             }
-            Payload::Broadcast {
-                msg_id,
-                message: payload,
-            } => {
-                let unique_id: UniqueMessageID = (message.src.to_string(), *msg_id);
-
-                // add to our messages and set it as known
-                let our_known = self.known.entry(self.node_id()).or_default();
-                self.messages
-                    .push((unique_id.to_owned(), payload.to_owned()));
-                our_known.insert(unique_id);
-
-                Ok(vec![Message::new_reply_to(
-                    message,
-                    Payload::BroadcastOk {
-                        in_reply_to: *msg_id,
-                    },
-                )])
+            Payload::Topology { topology } => {
+                let peers = topology
+                    .get(&self.node_id())
+                    .ok_or(Error::MalformedRequest)?;
+                self.broadcast()?.set_neighbours(peers.to_owned())?;
+                Ok(vec![message.reply(Payload::TopologyOk)])
             }
-            Payload::StartGossip => {
-                let node_id = self.node_id();
-                let responses = self
-                    .peers
-                    .iter()
-                    .filter_map(|peer| {
-                        let known = self.known.entry(peer.to_owned()).or_default();
-                        let msgs_to_send: Vec<(UniqueMessageID, Value)> = self
-                            .messages
-                            .iter()
-                            .cloned()
-                            .filter(|(msg_id, _)| !known.contains(msg_id))
-                            .collect();
-                        if msgs_to_send.is_empty() {
-                            None
-                        } else {
-                            Some(Message {
-                                src: node_id.clone(),
-                                dest: peer.to_owned(),
-                                body: Payload::Gossip {
-                                    payload: msgs_to_send,
-                                },
-                            })
-                        }
-                    })
-                    .collect();
-                Ok(responses)
+            Payload::Broadcast { message: payload } => {
+                self.broadcast()?.broadcast(payload.to_owned())?;
+                self.add(payload.to_owned());
+                Ok(vec![message.reply(Payload::BroadcastOk)])
             }
-            Payload::Gossip { payload } => {
-                let our_id = self.node_id();
-                let our_known = self.known.entry(our_id).or_default();
-                for (msg_id, value) in payload {
-                    if !our_known.contains(msg_id) {
-                        self.messages.push((msg_id.to_owned(), value.to_owned()));
-                        our_known.insert(msg_id.to_owned());
+            Payload::Gossip { payload, state } => {
+                if let Some((messages, seen)) = state {
+                    if self.broadcast()?.catch_up(seen) {
+                        messages.iter().for_each(|m| self.add(m.to_owned()));
                     }
                 }
-                Ok(vec![Message::new_reply_to(
-                    message,
-                    Payload::GossipOk {
-                        seen: our_known.to_owned(),
-                    },
-                )])
+                let ids = payload.iter().map(|(id, _)| id.to_owned()).collect();
+                for (_, value) in self
+                    .broadcast()?
+                    .receive(&message.src, payload.to_owned())?
+                {
+                    self.add(value);
+                }
+                let seen = self.broadcast()?.delivered().to_owned();
+                Ok(vec![message.reply(Payload::GossipOk { ids, seen })])
             }
-            Payload::GossipOk { seen } => {
-                let their_known = self.known.entry(message.src.to_owned()).or_default();
-                their_known.extend(seen.iter().cloned());
+            Payload::GossipOk { ids, seen } => {
+                self.broadcast()?.ack(&message.src, ids, seen)?;
                 Ok(vec![])
             }
-            Payload::Read { msg_id } => Ok(vec![Message::new_reply_to(
-                message,
-                Payload::ReadOk {
-                    messages: self.messages.iter().map(|m| m.1.to_owned()).collect(),
-                    in_reply_to: *msg_id,
-                },
-            )]),
-            Payload::BroadcastOk { .. } | Payload::ReadOk { .. } | Payload::TopologyOk { .. } => {
-                Ok(vec![])
+            Payload::Read => Ok(vec![message.reply(Payload::ReadOk {
+                messages: self.messages.values().cloned().collect(),
+            })]),
+            Payload::BroadcastOk | Payload::ReadOk { .. } | Payload::TopologyOk => Ok(vec![]),
+        }
+    }
+
+    fn on_timer(&mut self, timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let Some(Batch {
+            dest,
+            items,
+            with_state,
+        }) = self.broadcast()?.on_timer(timer)?
+        else {
+            return Ok(vec![]);
+        };
+        let state = with_state.then(|| {
+            let messages = self.messages.values().cloned().collect();
+            (
+                messages,
+                self.broadcast.as_ref().unwrap().delivered().to_owned(),
+            )
+        });
+        Ok(vec![Message {
+            src: self.node_id(),
+            dest,
+            body: Body::new(Payload::Gossip {
+                payload: items,
+                state,
+            }),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::sim::{NetworkConfig, Simulation};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn converges_after_a_partition() {
+        for seed in 0..4 {
+            let config = NetworkConfig {
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(20),
+                drop_rate: 0.1,
+                duplicate_rate: 0.1,
+            };
+            let mut sim = Simulation::<BroadcastActor>::new(5, seed, config).unwrap();
+            let nodes = sim.node_ids();
+            // a ring, so that every node has two ways to get every message
+            let topology: HashMap<ActorID, Vec<ActorID>> = nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| {
+                    let next = nodes[(i + 1) % nodes.len()].to_owned();
+                    let prev = nodes[(i + nodes.len() - 1) % nodes.len()].to_owned();
+                    (node.to_owned(), vec![prev, next])
+                })
+                .collect();
+            for node in &nodes {
+                let topology = topology.to_owned();
+                sim.request("c1", node, &Body::new(Payload::Topology { topology }));
+            }
+            sim.run_for(Duration::from_millis(100));
+
+            sim.partition(&[nodes[..2].to_vec(), nodes[2..].to_vec()]);
+            for message in 0..50 {
+                let node = &nodes[message % nodes.len()];
+                let message = json!(message);
+                sim.request("c1", node, &Body::new(Payload::Broadcast { message }));
+                sim.run_for(Duration::from_millis(20));
+            }
+            sim.run_for(Duration::from_secs(1));
+            let n0 = sim.actor("n0").unwrap();
+            assert!(n0.messages.len() < 50);
+
+            sim.heal();
+            sim.run_for(Duration::from_secs(5));
+            let reads: Vec<_> = nodes
+                .iter()
+                .map(|node| sim.request("c2", node, &Body::new(Payload::Read)))
+                .collect();
+            sim.run_for(Duration::from_secs(1));
+            for msg_id in reads {
+                let reply = sim.reply_to("c2", msg_id).unwrap();
+                let mut messages: Vec<u64> =
+                    serde_json::from_value(reply.body["messages"].to_owned()).unwrap();
+                messages.sort_unstable();
+                assert_eq!(messages, (0..50).collect::<Vec<_>>(), "seed {}", seed);
             }
         }
     }
@@ -1,10 +1,8 @@
-use std::sync::mpsc::Sender;
-
 use maelstrom::{
     actor::Actor,
     errors::Error,
-    message::{Message, MessageID},
-    runtime::Runtime,
+    message::{Body, Message},
+    runtime::{Handle, Runtime},
 };
 use serde::{Deserialize, Serialize};
 
@@ -19,28 +17,19 @@ struct EchoActor {
 }
 
 #[derive(Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
 enum Payload {
-    Echo {
-        #[serde(rename = "type")]
-        message_type: String,
-        msg_id: MessageID,
-        echo: String,
-    },
-    EchoAck {
-        #[serde(rename = "type")]
-        message_type: String,
-        in_reply_to: MessageID,
-        echo: String,
-    },
+    Echo { echo: String },
+    EchoOk { echo: String },
 }
 
 impl Actor for EchoActor {
-    type MessagePayload = Payload;
+    type MessagePayload = Body<Payload>;
 
     fn init(
         &mut self,
-        _tx: Sender<Message<Self::MessagePayload>>,
+        _handle: Handle<Self::MessagePayload>,
         node_id: String,
         _peers: Vec<String>,
     ) -> Result<(), Error> {
@@ -53,25 +42,11 @@ impl Actor for EchoActor {
         &mut self,
         message: &Message<Self::MessagePayload>,
     ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
-        match &message.body {
-            Payload::Echo {
-                message_type,
-                msg_id,
-                echo,
-            } if message_type == "echo" => {
-                let ack = Payload::EchoAck {
-                    message_type: "echo_ok".to_owned(),
-                    in_reply_to: *msg_id,
-                    echo: echo.to_owned(),
-                };
-                Ok(vec![Message::new_reply_to(message, ack)])
-            }
-            Payload::EchoAck {
-                message_type: _,
-                in_reply_to: _,
-                echo: _,
-            } => Ok(vec![]),
-            _ => Err(Error::NotSupported),
+        match &message.body.payload {
+            Payload::Echo { echo } => Ok(vec![message.reply(Payload::EchoOk {
+                echo: echo.to_owned(),
+            })]),
+            Payload::EchoOk { .. } => Ok(vec![]),
         }
     }
 }
@@ -1,39 +1,78 @@
-use std::{sync::mpsc::Sender, time::Duration};
-use maelstrom::{actor::Actor, crdt::{CrdtBase, Payload, CrdtMessageResponse}, message::Message, errors::Error, runtime::Runtime};
+use maelstrom::crdt::{self, GCounter};
 
-#[derive(Default)]
-struct GCounter(CrdtBase<u64>);
-impl Actor for GCounter {
-    type MessagePayload = Payload<u64>;
+/// Replicated as chosen with the `REPLICATION` environment variable, see [`crdt::start`]
+fn main() {
+    crdt::start::<GCounter>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::{
+        crdt::{Crdt, DeltaCrdtActor, Payload},
+        message::Body,
+        sim::{NetworkConfig, Simulation},
+    };
+    use serde_json::Value;
+    use std::time::Duration;
 
-    fn init(
-        &mut self,
-        tx: Sender<Message<Self::MessagePayload>>,
-        node_id: String,
-        peers: Vec<String>,
-    ) -> Result<(), Error> {
-        eprintln!("Initialized node {}", node_id);
-        self.0.node_id = Some(node_id);
-        self.0.peers = peers;
-        self.0.spawn_gossip_thread(tx, Duration::from_millis(150));
-        Ok(())
+    type Counter = DeltaCrdtActor<GCounter>;
+
+    /// Add 1..=60 across the nodes of a partitioned cluster, then heal it and read every node
+    fn run(seed: u64) -> (Simulation<Counter>, Vec<Value>) {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+        };
+        let mut sim = Simulation::<Counter>::new(5, seed, config).unwrap();
+        let nodes = sim.node_ids();
+        sim.partition(&[nodes[..2].to_vec(), nodes[2..].to_vec()]);
+        for delta in 1..=60u64 {
+            let node = &nodes[delta as usize % nodes.len()];
+            sim.request("c1", node, &Body::new(Payload::Add { delta }));
+            sim.run_for(Duration::from_millis(20));
+        }
+        sim.run_for(Duration::from_secs(1));
+        assert!(nodes
+            .iter()
+            .all(|node| sim.actor(node).unwrap().state().value() != 1830));
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(3));
+        let reads: Vec<_> = nodes
+            .iter()
+            .map(|node| sim.request("c2", node, &Body::new(Payload::<u64>::Read)))
+            .collect();
+        sim.run_for(Duration::from_secs(1));
+        let values = reads
+            .into_iter()
+            .map(|msg_id| sim.reply_to("c2", msg_id).unwrap().body["value"].to_owned())
+            .collect();
+        (sim, values)
     }
 
-    fn receive(
-        &mut self,
-        request: &Message<Self::MessagePayload>,
-    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
-        match self.0.process_crdt_payload(request) {
-            CrdtMessageResponse::Responses(responses) => Ok(responses),
-            CrdtMessageResponse::ReadRequest(seq) => {
-                let val: u64 = self.0.messages.iter().map(|m| m.1).sum();
-                Ok(vec![Message::new_reply_to(request, Payload::ReadOk { in_reply_to: seq, value: val.into() })])
-            },
+    #[test]
+    fn converges_after_a_partition() {
+        for seed in 0..4 {
+            let (_, values) = run(seed);
+            assert!(
+                values.iter().all(|v| *v == 1830),
+                "seed {}: {:?}",
+                seed,
+                values
+            );
         }
     }
-}
 
-fn main() {
-    let mut runtime = Runtime::<GCounter>::new();
-    runtime.start();
+    #[test]
+    fn runs_replay_from_their_seed() {
+        let (first, _) = run(7);
+        let (second, _) = run(7);
+        assert_eq!(
+            serde_json::to_string(first.replies()).unwrap(),
+            serde_json::to_string(second.replies()).unwrap()
+        );
+    }
 }
@@ -1,13 +1,15 @@
-use std::sync::mpsc::Sender;
-
 use maelstrom::{
     actor::Actor,
+    clock::unix_millis,
     errors::Error,
-    message::{Message, MessageID},
-    runtime::Runtime,
+    ids::{Snowflake, MAX_NODE_INDEX},
+    message::{Body, Message},
+    runtime::{Handle, Runtime},
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use serde_json::Value;
+use uuid::Builder;
 
 fn main() {
     let mut runtime: Runtime<IDActor> = Runtime::new();
@@ -17,35 +19,60 @@ fn main() {
 #[derive(Default)]
 struct IDActor {
     node_id: Option<String>,
+    handle: Option<Handle<Body<Payload>>>,
+    strategy: Strategy,
+}
+
+/// How ids are generated, chosen with the `ID_STRATEGY` environment variable
+#[derive(Default)]
+enum Strategy {
+    /// Random UUID v4, as a string
+    #[default]
+    Uuid,
+    /// Ordered 64-bit integer, see [`Snowflake`]
+    Snowflake(Snowflake),
 }
 
 #[derive(Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
 enum Payload {
-    Generate {
-        #[serde(rename = "type")]
-        message_type: String,
-        msg_id: MessageID,
-    },
-    GenerateAck {
-        #[serde(rename = "type")]
-        message_type: String,
-        in_reply_to: MessageID,
-        id: String,
-    },
+    Generate,
+    GenerateOk { id: Value },
 }
 
 impl Actor for IDActor {
-    type MessagePayload = Payload;
+    type MessagePayload = Body<Payload>;
 
     fn init(
         &mut self,
-        _tx: Sender<Message<Self::MessagePayload>>,
+        handle: Handle<Self::MessagePayload>,
         node_id: String,
         _peers: Vec<String>,
     ) -> Result<(), Error> {
         eprintln!("Initialized node {}", node_id);
+        self.strategy = match std::env::var("ID_STRATEGY").as_deref() {
+            Ok("snowflake") => match Snowflake::for_node(&node_id) {
+                Ok(generator) => Strategy::Snowflake(generator),
+                Err(_) => {
+                    eprintln!(
+                        "node id {} is not n<index> with an index up to {}, needed for snowflake ids: using uuid",
+                        node_id, MAX_NODE_INDEX
+                    );
+                    Strategy::Uuid
+                }
+            },
+            Ok("uuid") | Err(_) => Strategy::Uuid,
+            Ok(other) => {
+                eprintln!(
+                    "unknown ID_STRATEGY {}, expected uuid or snowflake: using uuid",
+                    other
+                );
+                Strategy::Uuid
+            }
+        };
         self.node_id = Some(node_id);
+        self.handle = Some(handle);
         Ok(())
     }
 
@@ -53,25 +80,21 @@ impl Actor for IDActor {
         &mut self,
         message: &Message<Self::MessagePayload>,
     ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
-        match &message.body {
-            Payload::Generate {
-                msg_id,
-                message_type,
-            } if message_type == "generate" => {
-                let uuid = Uuid::new_v4().to_string();
-                let ack = Payload::GenerateAck {
-                    message_type: "generate_ok".to_owned(),
-                    in_reply_to: *msg_id,
-                    id: uuid,
+        match &message.body.payload {
+            Payload::Generate => {
+                let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+                let id = match &mut self.strategy {
+                    Strategy::Uuid => Builder::from_random_bytes(handle.rng().gen())
+                        .into_uuid()
+                        .to_string()
+                        .into(),
+                    Strategy::Snowflake(generator) => {
+                        generator.next_id_at(unix_millis(handle.now())).into()
+                    }
                 };
-                Ok(vec![Message::new_reply_to(message, ack)])
+                Ok(vec![message.reply(Payload::GenerateOk { id })])
             }
-            Payload::GenerateAck {
-                message_type: _,
-                in_reply_to: _,
-                id: _,
-            } => Ok(vec![]),
-            _ => Err(Error::NotSupported),
+            Payload::GenerateOk { .. } => Ok(vec![]),
         }
     }
 }
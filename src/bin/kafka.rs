@@ -0,0 +1,329 @@
+use maelstrom::{
+    actor::{Actor, ActorID},
+    append_log::{Logs, Offset},
+    errors::{Error, ErrorBody},
+    message::{Body, Message, MessageID},
+    rpc::{self, RpcReply},
+    runtime::{Handle, Runtime},
+    timer::TimerId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+fn main() {
+    let mut runtime = Runtime::<KafkaActor>::new();
+    runtime.start();
+}
+
+/// How often leaders send their new entries to the other nodes
+const REPLICATION_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Every key has a leader, which allocates its offsets and replicates its log to the other
+/// nodes. Polls are served from the local replica, and committed offsets are gossiped.
+#[derive(Default)]
+struct KafkaActor {
+    node_id: Option<ActorID>,
+    node_ids: Vec<ActorID>,
+    handle: Option<Handle<Body<Payload>>>,
+    logs: Logs<Value>,
+    /// Sends forwarded to the leader of their key, by msg_id of the forwarded request
+    forwarded: HashMap<MessageID, Message<Body<Payload>>>,
+    /// Next offset each node holds, for the keys we lead
+    replicated: HashMap<ActorID, HashMap<String, Offset>>,
+    /// Committed offset each node has acknowledged, by key
+    committed_acked: HashMap<ActorID, HashMap<String, Offset>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Send {
+        key: String,
+        msg: Value,
+    },
+    SendOk {
+        offset: Offset,
+    },
+    Poll {
+        offsets: HashMap<String, Offset>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(Offset, Value)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, Offset>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, Offset>,
+    },
+    /// Entries of the keys the sender leads, and the committed offsets it knows of which the
+    /// receiver has not acknowledged yet
+    Replicate {
+        entries: Vec<(String, Offset, Value)>,
+        committed: BTreeMap<String, Offset>,
+    },
+    /// Next offset the sender holds, for the keys it was sent entries of, and the committed
+    /// offsets it was sent
+    ReplicateOk {
+        next_offsets: HashMap<String, Offset>,
+        committed: BTreeMap<String, Offset>,
+    },
+    /// Failure of a send forwarded to the leader of its key, relayed to the client
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+impl KafkaActor {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Node allocating the offsets of `key`
+    fn leader(&self, key: &str) -> &ActorID {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.node_ids[hasher.finish() as usize % self.node_ids.len()]
+    }
+
+    /// Send every other node the entries it is missing of the keys we lead, and the committed
+    /// offsets it has not acknowledged
+    fn replicate(&self) -> Vec<Message<Body<Payload>>> {
+        let node_id = self.node_id();
+        let committed = self.logs.committed_offsets();
+        self.node_ids
+            .iter()
+            .filter(|peer| **peer != node_id)
+            .filter_map(|peer| {
+                let next_offsets = self.replicated.get(peer);
+                let acked = self.committed_acked.get(peer);
+                let committed: BTreeMap<String, Offset> = committed
+                    .iter()
+                    .filter(|(key, offset)| {
+                        acked.and_then(|a| a.get(*key)).is_none_or(|a| a < offset)
+                    })
+                    .map(|(key, offset)| (key.to_owned(), *offset))
+                    .collect();
+                let entries: Vec<(String, Offset, Value)> = self
+                    .logs
+                    .keys()
+                    .filter(|key| *self.leader(key) == node_id)
+                    .flat_map(|key| {
+                        let from = next_offsets.and_then(|n| n.get(key)).copied();
+                        self.logs
+                            .read_from(key, from.unwrap_or_default())
+                            .into_iter()
+                            .map(|(offset, msg)| (key.to_owned(), offset, msg))
+                    })
+                    .collect();
+                if entries.is_empty() && committed.is_empty() {
+                    None
+                } else {
+                    Some(Message {
+                        src: node_id.clone(),
+                        dest: peer.to_owned(),
+                        body: Body::new(Payload::Replicate { entries, committed }),
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+impl Actor for KafkaActor {
+    type MessagePayload = Body<Payload>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: ActorID,
+        node_ids: Vec<ActorID>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.node_id = Some(node_id);
+        self.node_ids = node_ids;
+        handle.schedule_every(REPLICATION_INTERVAL)?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Send { key, msg } => {
+                let leader = self.leader(key).to_owned();
+                if leader == self.node_id() {
+                    let offset = self.logs.append(key, msg.to_owned());
+                    return Ok(vec![message.reply(Payload::SendOk { offset })]);
+                }
+                // answered once the leader replies, in on_reply
+                let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+                let request = Payload::Send {
+                    key: key.to_owned(),
+                    msg: msg.to_owned(),
+                };
+                let msg_id = handle.call(leader, &request, rpc::DEFAULT_TIMEOUT)?;
+                self.forwarded.insert(msg_id, message.to_owned());
+                Ok(vec![])
+            }
+            Payload::Poll { offsets } => {
+                let msgs = offsets
+                    .iter()
+                    .map(|(key, offset)| (key.to_owned(), self.logs.read_from(key, *offset)))
+                    .collect();
+                Ok(vec![message.reply(Payload::PollOk { msgs })])
+            }
+            Payload::CommitOffsets { offsets } => {
+                for (key, offset) in offsets {
+                    self.logs.commit(key, *offset);
+                }
+                Ok(vec![message.reply(Payload::CommitOffsetsOk)])
+            }
+            Payload::ListCommittedOffsets { keys } => {
+                let offsets = keys
+                    .iter()
+                    .filter_map(|key| Some((key.to_owned(), self.logs.committed(key)?)))
+                    .collect();
+                Ok(vec![message.reply(Payload::ListCommittedOffsetsOk { offsets })])
+            }
+            Payload::Replicate { entries, committed } => {
+                let mut next_offsets = HashMap::new();
+                for (key, offset, msg) in entries {
+                    // entries past a gap are sent again once the gap is filled
+                    self.logs.insert(key, *offset, msg.to_owned());
+                    next_offsets.insert(key.to_owned(), self.logs.next_offset(key));
+                }
+                for (key, offset) in committed {
+                    self.logs.commit(key, *offset);
+                }
+                Ok(vec![message.reply(Payload::ReplicateOk {
+                    next_offsets,
+                    committed: committed.to_owned(),
+                })])
+            }
+            Payload::ReplicateOk {
+                next_offsets,
+                committed,
+            } => {
+                let replicated = self.replicated.entry(message.src.to_owned()).or_default();
+                for (key, offset) in next_offsets {
+                    let ours = replicated.entry(key.to_owned()).or_default();
+                    *ours = (*ours).max(*offset);
+                }
+                let acked = self
+                    .committed_acked
+                    .entry(message.src.to_owned())
+                    .or_default();
+                for (key, offset) in committed {
+                    let ours = acked.entry(key.to_owned()).or_default();
+                    *ours = (*ours).max(*offset);
+                }
+                Ok(vec![])
+            }
+            Payload::SendOk { .. }
+            | Payload::PollOk { .. }
+            | Payload::CommitOffsetsOk
+            | Payload::ListCommittedOffsetsOk { .. }
+            | Payload::Error { .. } => Ok(vec![]),
+        }
+    }
+
+    fn on_reply(&mut self, reply: RpcReply) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let request = match self.forwarded.remove(&reply.request_id) {
+            Some(request) => request,
+            None => return Ok(vec![]),
+        };
+        let offset = match reply.decode::<Body<Payload>>().map(|msg| msg.body.payload) {
+            Ok(Payload::SendOk { offset }) => Ok(offset),
+            Ok(_) => Err(Error::Crash),
+            Err(e) => Err(e),
+        };
+        match offset {
+            Ok(offset) => Ok(vec![request.reply(Payload::SendOk { offset })]),
+            // relayed as is, so that a timeout stays indefinite: the message may have been appended
+            Err(e) => {
+                let ErrorBody { code, text, .. } = ErrorBody::new(&e, reply.request_id);
+                Ok(vec![request.reply(Payload::Error { code, text })])
+            }
+        }
+    }
+
+    fn on_timer(&mut self, _timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        Ok(self.replicate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::sim::{NetworkConfig, Simulation};
+    use serde_json::json;
+
+    fn cluster() -> Simulation<KafkaActor> {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        };
+        Simulation::new(2, 3, config).unwrap()
+    }
+
+    /// A key led by `node`
+    fn key_led_by(sim: &Simulation<KafkaActor>, node: &str) -> String {
+        let actor = sim.actor(node).unwrap();
+        (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| actor.leader(key) == node)
+            .unwrap()
+    }
+
+    #[test]
+    fn forwarded_sends_which_time_out_are_answered() {
+        let mut sim = cluster();
+        let key = key_led_by(&sim, "n1");
+        sim.partition(&[vec!["n0".to_owned()], vec!["n1".to_owned()]]);
+        let send = Payload::Send { key, msg: json!(1) };
+        let msg_id = sim.request("c1", "n0", &Body::new(send));
+        sim.run_for(rpc::DEFAULT_TIMEOUT * 2);
+        let reply = sim.reply_to("c1", msg_id).unwrap();
+        assert_eq!(reply.body["type"], "error");
+        assert_eq!(reply.body["code"], 0);
+    }
+
+    #[test]
+    fn committed_offsets_are_sent_until_acknowledged() {
+        let mut sim = cluster();
+        let key = key_led_by(&sim, "n0");
+        let send = Payload::Send {
+            key: key.to_owned(),
+            msg: json!(1),
+        };
+        sim.request("c1", "n0", &Body::new(send));
+        let commit = Payload::CommitOffsets {
+            offsets: HashMap::from([(key.to_owned(), 0)]),
+        };
+        sim.request("c1", "n0", &Body::new(commit));
+        sim.run_for(Duration::from_millis(500));
+        assert!(sim.actor("n0").unwrap().replicate().is_empty());
+
+        let list = Payload::ListCommittedOffsets { keys: vec![key] };
+        let msg_id = sim.request("c1", "n1", &Body::new(list));
+        sim.run_for(Duration::from_millis(100));
+        let reply = sim.reply_to("c1", msg_id).unwrap();
+        assert_eq!(reply.body["offsets"].as_object().unwrap().len(), 1);
+    }
+}
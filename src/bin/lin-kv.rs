@@ -0,0 +1,20 @@
+use maelstrom::{paxos::MultiPaxos, raft::Raft, runtime::Runtime, state_machine::KvStateMachine};
+
+/// Linearizable key-value store: every request, reads included, goes through the replicated
+/// log, and is answered by the leader once applied.
+///
+/// The consensus protocol replicating the store is chosen with the `CONSENSUS` environment
+/// variable: `raft` (the default) or `paxos`. Any other value falls back to raft, with a warning.
+fn main() {
+    match std::env::var("CONSENSUS").as_deref() {
+        Ok("raft") | Err(_) => Runtime::<Raft<KvStateMachine>>::new().start(),
+        Ok("paxos") => Runtime::<MultiPaxos<KvStateMachine>>::new().start(),
+        Ok(other) => {
+            eprintln!(
+                "unknown CONSENSUS {}, expected raft or paxos: using raft",
+                other
+            );
+            Runtime::<Raft<KvStateMachine>>::new().start()
+        }
+    }
+}
@@ -0,0 +1,6 @@
+use maelstrom::crdt::{self, PnCounter};
+
+/// Replicated as chosen with the `REPLICATION` environment variable, see [`crdt::start`]
+fn main() {
+    crdt::start::<PnCounter>();
+}
@@ -0,0 +1,194 @@
+use maelstrom::{
+    async_runtime::{AsyncActor, AsyncRuntime, Context},
+    errors::Error,
+    message::{Body, Message},
+    services::{AsyncKvClient, LIN_KV, LWW_KV},
+    txn::ListOp,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+fn main() {
+    let mut runtime = AsyncRuntime::<ListAppendActor>::new();
+    runtime.start();
+}
+
+type Key = u64;
+type Element = u64;
+
+/// Key of the root in lin-kv
+const ROOT: &str = "root";
+/// How many times to look for a thunk which lww-kv has not made visible yet
+const THUNK_READ_ATTEMPTS: usize = 10;
+const THUNK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Id of the thunk holding the list of every key. JSON objects only have string keys.
+type Root = BTreeMap<String, String>;
+
+/// Serializable transactions in the style of Datomic: lists are immutable thunks stored in
+/// lww-kv, and the whole database is a single root in lin-kv mapping keys to thunks.
+/// A transaction writes the thunks of the lists it appended to, then swaps the root it read for
+/// one pointing to them. If another transaction swapped it first, it fails with `TxnConflict`.
+struct ListAppendActor {
+    /// Random, so that thunk ids of a node do not collide with those of a previous run of it,
+    /// which are still referenced by the root
+    incarnation: u64,
+    thunk_ids: AtomicU64,
+}
+
+impl Default for ListAppendActor {
+    fn default() -> Self {
+        Self {
+            incarnation: rand::random(),
+            thunk_ids: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Txn { txn: Vec<ListOp<Key, Element>> },
+    TxnOk { txn: Vec<ListOp<Key, Element>> },
+}
+
+impl ListAppendActor {
+    /// Thunks are never overwritten, so every one gets a fresh id
+    fn next_thunk_id(&self, node_id: &str) -> String {
+        let id = self.thunk_ids.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:016x}-{}", node_id, self.incarnation, id)
+    }
+
+    async fn read_root(lin_kv: &AsyncKvClient) -> Result<Option<Root>, Error> {
+        match lin_kv.read(ROOT).await {
+            Ok(root) => serde_json::from_value(root)
+                .map(Some)
+                .map_err(|_| Error::Crash),
+            Err(Error::KeyDoesNotExist) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// lww-kv is eventually consistent: a thunk written by another node may not be visible yet
+    async fn read_thunk(
+        ctx: &Context,
+        lww_kv: &AsyncKvClient,
+        id: &str,
+    ) -> Result<Vec<Element>, Error> {
+        for _ in 0..THUNK_READ_ATTEMPTS {
+            match lww_kv.read(id).await {
+                Ok(list) => return serde_json::from_value(list).map_err(|_| Error::Crash),
+                Err(Error::KeyDoesNotExist) => ctx.sleep(THUNK_RETRY_DELAY).await,
+                Err(e) => return Err(e),
+            }
+        }
+        // nothing was written yet, so giving up is definite
+        Err(Error::Abort)
+    }
+
+    async fn transact(
+        &self,
+        ctx: &Context,
+        txn: &[ListOp<Key, Element>],
+    ) -> Result<Vec<ListOp<Key, Element>>, Error> {
+        let lin_kv = AsyncKvClient::new(LIN_KV, ctx.clone());
+        let lww_kv = AsyncKvClient::new(LWW_KV, ctx.clone());
+        let root = Self::read_root(&lin_kv).await?;
+        let mut new_root = root.to_owned().unwrap_or_default();
+
+        let mut lists: HashMap<Key, Option<Vec<Element>>> = HashMap::new();
+        let mut appended = BTreeSet::new();
+        let mut completed = Vec::with_capacity(txn.len());
+        for op in txn {
+            let key = *op.key();
+            let list = match lists.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let list = match new_root.get(&key.to_string()) {
+                        Some(id) => Some(Self::read_thunk(ctx, &lww_kv, id).await?),
+                        None => None,
+                    };
+                    entry.insert(list)
+                }
+            };
+            match op {
+                ListOp::Read { .. } => completed.push(ListOp::Read {
+                    key,
+                    value: list.to_owned(),
+                }),
+                ListOp::Append { element, .. } => {
+                    list.get_or_insert_with(Vec::new).push(*element);
+                    appended.insert(key);
+                    completed.push(op.to_owned());
+                }
+            }
+        }
+        if appended.is_empty() {
+            return Ok(completed);
+        }
+
+        for key in appended {
+            let id = self.next_thunk_id(ctx.node_id());
+            lww_kv
+                .write(
+                    id.to_owned(),
+                    serde_json::to_value(&lists[&key]).map_err(|_| Error::Crash)?,
+                )
+                .await?;
+            new_root.insert(key.to_string(), id);
+        }
+        let from = serde_json::to_value(root.unwrap_or_default()).map_err(|_| Error::Crash)?;
+        let to = serde_json::to_value(new_root).map_err(|_| Error::Crash)?;
+        match lin_kv.cas(ROOT, from, to, true).await {
+            Ok(()) => Ok(completed),
+            Err(Error::PreconditionFailed) => Err(Error::TxnConflict),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsyncActor for ListAppendActor {
+    type MessagePayload = Body<Payload>;
+
+    async fn init(&self, _ctx: Context, node_id: String, _peers: Vec<String>) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        Ok(())
+    }
+
+    async fn receive(
+        &self,
+        ctx: Context,
+        message: Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Txn { txn } => {
+                let txn = self.transact(&ctx, txn).await?;
+                Ok(vec![message.reply(Payload::TxnOk { txn })])
+            }
+            Payload::TxnOk { .. } => Ok(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thunk_ids_are_not_reused_after_a_restart() {
+        let mut ids = BTreeSet::new();
+        // the same node, started twice
+        for actor in [ListAppendActor::default(), ListAppendActor::default()] {
+            for _ in 0..100 {
+                let id = actor.next_thunk_id("n1");
+                assert!(id.starts_with("n1-"));
+                assert!(ids.insert(id));
+            }
+        }
+    }
+}
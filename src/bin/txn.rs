@@ -0,0 +1,217 @@
+use maelstrom::{
+    actor::{Actor, ActorID},
+    crdt::{Crdt, LwwRegister},
+    errors::Error,
+    message::{Body, Message},
+    runtime::{Handle, Runtime},
+    timer::TimerId,
+    txn::MicroOp,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+fn main() {
+    let mut runtime = Runtime::<TxnActor>::new().with_piggybacked_clock();
+    runtime.start();
+}
+
+/// How often nodes send their registers to each other
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+type Key = u64;
+type Value = u64;
+
+/// Totally available key-value store: every node runs transactions on its own replica, and
+/// every key is an [`LwwRegister`], so replicas converge on its latest write.
+///
+/// The writes of a transaction are installed together once it has run, timestamped by the clock
+/// of the node. That clock is piggybacked on replication, so a node which sees a write moves its
+/// clock past it and timestamps follow causality. This gives read committed, and therefore read
+/// uncommitted, isolation.
+#[derive(Default)]
+struct TxnActor {
+    node_id: Option<ActorID>,
+    peers: Vec<ActorID>,
+    handle: Option<Handle<Body<Payload>>>,
+    registers: HashMap<Key, LwwRegister<Value>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Txn {
+        txn: Vec<MicroOp<Key, Value>>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp<Key, Value>>,
+    },
+    /// Every register of the sender
+    Replicate {
+        registers: Vec<(Key, LwwRegister<Value>)>,
+    },
+}
+
+impl TxnActor {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Run `txn` against our registers, filling in the values read
+    fn execute(&mut self, txn: &[MicroOp<Key, Value>]) -> Result<Vec<MicroOp<Key, Value>>, Error> {
+        let mut writes = HashMap::new();
+        let completed = txn
+            .iter()
+            .map(|op| match op {
+                MicroOp::Read { key, .. } => MicroOp::Read {
+                    key: *key,
+                    value: writes
+                        .get(key)
+                        .or_else(|| self.registers.get(key).and_then(LwwRegister::get))
+                        .copied(),
+                },
+                MicroOp::Write { key, value } => {
+                    writes.insert(*key, *value);
+                    op.to_owned()
+                }
+            })
+            .collect();
+        let node_id = self.node_id();
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        let now = handle.timestamp();
+        for (key, value) in writes {
+            self.registers
+                .entry(key)
+                .or_default()
+                .apply_local(&node_id, now, value);
+        }
+        Ok(completed)
+    }
+}
+
+impl Actor for TxnActor {
+    type MessagePayload = Body<Payload>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: ActorID,
+        node_ids: Vec<ActorID>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.peers = node_ids.into_iter().filter(|n| *n != node_id).collect();
+        self.node_id = Some(node_id);
+        handle.schedule_every(GOSSIP_INTERVAL)?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Txn { txn } => {
+                let txn = self.execute(txn)?;
+                Ok(vec![message.reply(Payload::TxnOk { txn })])
+            }
+            Payload::Replicate { registers } => {
+                for (key, register) in registers {
+                    self.registers.entry(*key).or_default().merge(register);
+                }
+                Ok(vec![])
+            }
+            Payload::TxnOk { .. } => Ok(vec![]),
+        }
+    }
+
+    fn on_timer(&mut self, _timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        if self.registers.is_empty() {
+            return Ok(vec![]);
+        }
+        let registers: Vec<(Key, LwwRegister<Value>)> = self
+            .registers
+            .iter()
+            .map(|(key, register)| (*key, register.to_owned()))
+            .collect();
+        let node_id = self.node_id();
+        Ok(self
+            .peers
+            .iter()
+            .map(|peer| Message {
+                src: node_id.to_owned(),
+                dest: peer.to_owned(),
+                body: Body::new(Payload::Replicate {
+                    registers: registers.to_owned(),
+                }),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::sim::{NetworkConfig, Simulation};
+    use serde_json::json;
+
+    fn txn(sim: &mut Simulation<TxnActor>, node: &str, txn: Vec<MicroOp<Key, Value>>) -> u64 {
+        sim.request("c1", node, &Body::new(Payload::Txn { txn }))
+    }
+
+    #[test]
+    fn writes_made_after_seeing_another_win_everywhere() {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+        };
+        let mut sim = Simulation::<TxnActor>::new(3, 7, config)
+            .unwrap()
+            .with_piggybacked_clock();
+        txn(&mut sim, "n0", vec![MicroOp::Write { key: 1, value: 1 }]);
+        sim.run_for(Duration::from_secs(1));
+        let overwrite = txn(
+            &mut sim,
+            "n1",
+            vec![
+                MicroOp::Read {
+                    key: 1,
+                    value: None,
+                },
+                MicroOp::Write { key: 1, value: 2 },
+                MicroOp::Read {
+                    key: 1,
+                    value: None,
+                },
+            ],
+        );
+        sim.run_for(Duration::from_secs(1));
+        let reply = sim.reply_to("c1", overwrite).unwrap();
+        assert_eq!(
+            reply.body["txn"],
+            json!([["r", 1, 1], ["w", 1, 2], ["r", 1, 2]])
+        );
+
+        let reads: Vec<_> = sim
+            .node_ids()
+            .iter()
+            .map(|node| {
+                let read = MicroOp::Read {
+                    key: 1,
+                    value: None,
+                };
+                txn(&mut sim, node, vec![read])
+            })
+            .collect();
+        sim.run_for(Duration::from_millis(100));
+        for msg_id in reads {
+            let reply = sim.reply_to("c1", msg_id).unwrap();
+            assert_eq!(reply.body["txn"], json!([["r", 1, 2]]));
+            // the clock is only piggybacked on messages between nodes
+            assert!(reply.body.get("hlc").is_none());
+        }
+    }
+}
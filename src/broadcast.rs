@@ -0,0 +1,619 @@
+//! Reliable broadcast over an overlay of neighbours.
+//!
+//! Every item is delivered once on every node, then relayed to the neighbours it did not come
+//! from. Each neighbour has an outbox of the items it has not acknowledged: they are sent in
+//! batches, and sent again with an exponential backoff until acknowledged, so items make it
+//! through lost messages and partitions.
+//!
+//! Items are identified by the node which broadcast them and its count of broadcasts, so what a
+//! node delivered is summed up by a [`VersionVector`], along with the few items delivered past a
+//! gap. Items are dropped once every neighbour acknowledged them: a neighbour added later is
+//! sent the state of the actor instead, until it acknowledges having seen what we had delivered.
+//!
+//! [`ReliableBroadcast`] does not send messages itself: the actor wraps the [`Batch`]es it
+//! returns into its own payload, and hands back the items and acknowledgements it receives.
+use crate::{actor::ActorID, clock::VersionVector, errors::Error, runtime::Handle, timer::TimerId};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+    time::Duration,
+};
+
+/// How items are batched and retransmitted
+#[derive(Clone, Debug)]
+pub struct BroadcastConfig {
+    /// How long new items wait for others to share a batch with
+    pub batch_delay: Duration,
+    /// How long to wait for an acknowledgement before sending a batch again
+    pub initial_backoff: Duration,
+    /// The wait doubles with every batch left unacknowledged, up to this
+    pub max_backoff: Duration,
+    /// Cap on the items sent in a single batch
+    pub max_batch: usize,
+    /// Number of neighbours each item is relayed to, picked at random. All of them if `None`.
+    /// Below the degree of the overlay, an item may miss the nodes only reachable through the
+    /// neighbours which were not picked.
+    pub fanout: Option<usize>,
+    /// Whether items received from a neighbour are relayed to the others. Not needed when every
+    /// node is a neighbour of every other one.
+    pub relay: bool,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            batch_delay: Duration::from_millis(50),
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(2),
+            max_batch: 100,
+            fanout: None,
+            relay: true,
+        }
+    }
+}
+
+/// Identifies an item: the node which broadcast it, and its count of broadcasts
+pub type ItemID = (ActorID, u64);
+
+/// Items to send to `dest`
+#[derive(Clone, Debug)]
+pub struct Batch<V> {
+    pub dest: ActorID,
+    pub items: Vec<(ItemID, V)>,
+    /// Whether `dest` is missing items we no longer hold, so that the actor is to send it its
+    /// state along, with [`ReliableBroadcast::delivered`]
+    pub with_state: bool,
+}
+
+/// Items a neighbour has not acknowledged yet
+struct Outbox<V> {
+    pending: BTreeMap<ItemID, V>,
+    /// Fires when the next batch is due
+    timer: Option<TimerId>,
+    backoff: Duration,
+    /// Last item of the previous batch. Batches go round the outbox, so that items past the
+    /// batch cap are sent even while acknowledgements are lost.
+    cursor: Option<ItemID>,
+    /// What we had delivered when the neighbour was added, until it acknowledges having seen it
+    catch_up: Option<VersionVector>,
+}
+
+impl<V> Outbox<V> {
+    fn new(backoff: Duration) -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            timer: None,
+            backoff,
+            cursor: None,
+            catch_up: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.catch_up.is_none()
+    }
+}
+
+/// Reliable broadcast of `V` values, for an actor sending `T` payloads
+pub struct ReliableBroadcast<V, T> {
+    handle: Handle<T>,
+    config: BroadcastConfig,
+    neighbours: Vec<ActorID>,
+    /// Items delivered without a gap, by origin
+    delivered: VersionVector,
+    /// Items delivered past a gap, folded into `delivered` once it is filled
+    out_of_order: BTreeSet<ItemID>,
+    outboxes: HashMap<ActorID, Outbox<V>>,
+    /// Neighbour of every outbox timer
+    timers: HashMap<TimerId, ActorID>,
+}
+
+impl<V: Clone, T: Serialize> ReliableBroadcast<V, T> {
+    pub fn new(handle: Handle<T>, config: BroadcastConfig) -> Self {
+        Self {
+            handle,
+            config,
+            neighbours: vec![],
+            delivered: VersionVector::new(),
+            out_of_order: BTreeSet::new(),
+            outboxes: HashMap::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    /// Nodes items are relayed to from now on. New neighbours are sent our state, once we have
+    /// delivered anything, and nothing more is sent to the former ones.
+    pub fn set_neighbours(&mut self, neighbours: Vec<ActorID>) -> Result<(), Error> {
+        let added: Vec<ActorID> = neighbours
+            .iter()
+            .filter(|n| !self.neighbours.contains(n))
+            .cloned()
+            .collect();
+        let removed: Vec<ActorID> = self
+            .outboxes
+            .keys()
+            .filter(|n| !neighbours.contains(n))
+            .cloned()
+            .collect();
+        for peer in removed {
+            if let Some(timer) = self.outboxes.remove(&peer).and_then(|o| o.timer) {
+                self.timers.remove(&timer);
+                self.handle.cancel(timer)?;
+            }
+        }
+        self.neighbours = neighbours;
+        if self.delivered == VersionVector::new() && self.out_of_order.is_empty() {
+            return Ok(());
+        }
+        for peer in added {
+            let delivered = self.delivered.to_owned();
+            self.outbox(&peer).catch_up = Some(delivered);
+            self.arm(&peer, self.config.batch_delay)?;
+        }
+        Ok(())
+    }
+
+    pub fn neighbours(&self) -> &[ActorID] {
+        &self.neighbours
+    }
+
+    /// Items delivered so far, but for those past a gap
+    pub fn delivered(&self) -> &VersionVector {
+        &self.delivered
+    }
+
+    /// Number of items still waiting for an acknowledgement, across every neighbour
+    pub fn unacknowledged(&self) -> usize {
+        self.outboxes.values().map(|o| o.pending.len()).sum()
+    }
+
+    /// Number of items delivered past a gap, waiting for it to be filled
+    pub fn out_of_order(&self) -> usize {
+        self.out_of_order.len()
+    }
+
+    /// Deliver an item accepted by this node, and send it to our neighbours. Returns its id.
+    pub fn broadcast(&mut self, value: V) -> Result<ItemID, Error> {
+        let node_id = self.handle.node_id().to_owned();
+        let id = (node_id.to_owned(), self.delivered.get(&node_id) + 1);
+        self.deliver(None, id.to_owned(), value)?;
+        Ok(id)
+    }
+
+    /// Deliver the items sent by `from` which are new to us, and relay them. Returns the new
+    /// ones. Every item is to be acknowledged, known ones included: they may be sent again when
+    /// an acknowledgement is lost.
+    pub fn receive(
+        &mut self,
+        from: &ActorID,
+        items: Vec<(ItemID, V)>,
+    ) -> Result<Vec<(ItemID, V)>, Error> {
+        let mut delivered = vec![];
+        for (id, value) in items {
+            // `from` has it, whether it got it before or after us
+            if let Some(outbox) = self.outboxes.get_mut(from) {
+                outbox.pending.remove(&id);
+            }
+            if self.deliver(Some(from), id.to_owned(), value.to_owned())? {
+                delivered.push((id, value));
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Count as delivered every item seen by a neighbour whose state the actor merged into its
+    /// own. Returns whether it had seen items we had not, so that its state is worth merging.
+    pub fn catch_up(&mut self, seen: &VersionVector) -> bool {
+        if self.delivered.missing(seen).is_empty() {
+            return false;
+        }
+        self.delivered.merge(seen);
+        self.fill_gaps();
+        true
+    }
+
+    /// Stop sending `from` the items it acknowledged, by id or as `seen`. Once it made progress,
+    /// a neighbour gets its next batch without waiting for the backoff.
+    pub fn ack(
+        &mut self,
+        from: &ActorID,
+        ids: &[ItemID],
+        seen: &VersionVector,
+    ) -> Result<(), Error> {
+        let Some(outbox) = self.outboxes.get_mut(from) else {
+            return Ok(());
+        };
+        let before = outbox.pending.len();
+        for id in ids {
+            outbox.pending.remove(id);
+        }
+        outbox
+            .pending
+            .retain(|(origin, seq), _| !seen.contains(origin, *seq));
+        let caught_up = outbox
+            .catch_up
+            .take_if(|delivered| seen.dominates(delivered))
+            .is_some();
+        if outbox.pending.len() == before && !caught_up {
+            return Ok(());
+        }
+        outbox.backoff = self.config.initial_backoff;
+        if outbox.is_empty() {
+            if let Some(timer) = outbox.timer.take() {
+                self.timers.remove(&timer);
+                self.handle.cancel(timer)?;
+            }
+            Ok(())
+        } else {
+            self.arm(from, self.config.batch_delay)
+        }
+    }
+
+    /// Called with every timer of the actor. Returns the batch due if the timer was an outbox's.
+    pub fn on_timer(&mut self, timer: TimerId) -> Result<Option<Batch<V>>, Error> {
+        let Some(peer) = self.timers.remove(&timer) else {
+            return Ok(None);
+        };
+        let Some(outbox) = self.outboxes.get_mut(&peer) else {
+            return Ok(None);
+        };
+        outbox.timer = None;
+        if outbox.is_empty() {
+            return Ok(None);
+        }
+        let after = match &outbox.cursor {
+            Some(cursor) => (Bound::Excluded(cursor), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let items: Vec<(ItemID, V)> = outbox
+            .pending
+            .range::<ItemID, _>(after)
+            .chain(outbox.pending.iter())
+            .take(self.config.max_batch.min(outbox.pending.len()))
+            .map(|(id, value)| (id.to_owned(), value.to_owned()))
+            .collect();
+        if let Some((id, _)) = items.last() {
+            outbox.cursor = Some(id.to_owned());
+        }
+        let with_state = outbox.catch_up.is_some();
+        let backoff = outbox.backoff;
+        outbox.backoff = (backoff * 2).min(self.config.max_backoff);
+        self.arm(&peer, backoff)?;
+        Ok(Some(Batch {
+            dest: peer,
+            items,
+            with_state,
+        }))
+    }
+
+    /// Returns false if the item was delivered already
+    fn deliver(&mut self, from: Option<&ActorID>, id: ItemID, value: V) -> Result<bool, Error> {
+        let (origin, seq) = &id;
+        if self.delivered.contains(origin, *seq) || self.out_of_order.contains(&id) {
+            return Ok(false);
+        }
+        if self.delivered.observe(origin, *seq) {
+            self.fill_gaps();
+        } else {
+            self.out_of_order.insert(id.to_owned());
+        }
+        if from.is_some() && !self.config.relay {
+            return Ok(true);
+        }
+        let mut targets: Vec<ActorID> = self
+            .neighbours
+            .iter()
+            .filter(|n| Some(*n) != from)
+            .cloned()
+            .collect();
+        if let Some(fanout) = self.config.fanout.filter(|f| *f < targets.len()) {
+            let (picked, _) = targets.partial_shuffle(&mut *self.handle.rng(), fanout);
+            targets = picked.to_vec();
+        }
+        for peer in targets {
+            self.outbox(&peer)
+                .pending
+                .insert(id.to_owned(), value.to_owned());
+            if self.outboxes[&peer].timer.is_none() {
+                self.arm(&peer, self.config.batch_delay)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Fold the items past a gap into `delivered`, as far as the gaps before them are filled
+    fn fill_gaps(&mut self) {
+        // ordered by origin then by number, so a single pass folds every run
+        let delivered = &mut self.delivered;
+        self.out_of_order.retain(|(origin, seq)| {
+            !(delivered.contains(origin, *seq) || delivered.observe(origin, *seq))
+        });
+    }
+
+    fn outbox(&mut self, peer: &ActorID) -> &mut Outbox<V> {
+        let initial_backoff = self.config.initial_backoff;
+        self.outboxes
+            .entry(peer.to_owned())
+            .or_insert_with(|| Outbox::new(initial_backoff))
+    }
+
+    /// Send the next batch to `peer` after `delay`, instead of when it was due
+    fn arm(&mut self, peer: &ActorID, delay: Duration) -> Result<(), Error> {
+        let outbox = self.outboxes.get_mut(peer).ok_or(Error::Crash)?;
+        if let Some(timer) = outbox.timer.take() {
+            self.timers.remove(&timer);
+            self.handle.cancel(timer)?;
+        }
+        let timer = self.handle.schedule_once(delay)?;
+        outbox.timer = Some(timer);
+        self.timers.insert(timer, peer.to_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Event;
+    use std::sync::mpsc::{self, Receiver};
+
+    type Broadcast = ReliableBroadcast<char, ()>;
+
+    /// Node n0 with neighbours `neighbours`, along with the events it sends the runtime
+    fn node(config: BroadcastConfig, neighbours: &[&str]) -> (Broadcast, Receiver<Event<()>>) {
+        let (tx, rx) = mpsc::channel();
+        let mut broadcast = ReliableBroadcast::new(Handle::new("n0".to_owned(), tx), config);
+        let neighbours = neighbours.iter().map(|n| n.to_string()).collect();
+        broadcast.set_neighbours(neighbours).unwrap();
+        (broadcast, rx)
+    }
+
+    fn id(origin: &str, seq: u64) -> ItemID {
+        (origin.to_owned(), seq)
+    }
+
+    fn vv(entries: &[(&str, u64)]) -> VersionVector {
+        entries
+            .iter()
+            .map(|(node_id, seq)| (node_id.to_string(), *seq))
+            .collect()
+    }
+
+    fn outbox<'a>(broadcast: &'a Broadcast, peer: &str) -> &'a Outbox<char> {
+        &broadcast.outboxes[peer]
+    }
+
+    /// Fire the timer of the outbox of `peer`, returning the batch sent
+    fn fire(broadcast: &mut Broadcast, peer: &str) -> Batch<char> {
+        let timer = outbox(broadcast, peer).timer.unwrap();
+        let batch = broadcast.on_timer(timer).unwrap().unwrap();
+        assert_eq!(batch.dest, peer);
+        batch
+    }
+
+    /// Numbers of the items of the next batch sent to n1
+    fn fire_n1(broadcast: &mut Broadcast) -> Vec<u64> {
+        let batch = fire(broadcast, "n1");
+        batch.items.into_iter().map(|((_, seq), _)| seq).collect()
+    }
+
+    fn ack(broadcast: &mut Broadcast, seqs: &[u64]) {
+        let ids: Vec<ItemID> = seqs.iter().map(|seq| id("n0", *seq)).collect();
+        broadcast
+            .ack(&"n1".to_owned(), &ids, &VersionVector::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn acknowledged_items_are_no_longer_sent() {
+        let (mut broadcast, _events) = node(BroadcastConfig::default(), &["n1"]);
+        for value in ['a', 'b', 'c'] {
+            broadcast.broadcast(value).unwrap();
+        }
+        assert_eq!(broadcast.delivered(), &vv(&[("n0", 3)]));
+        assert_eq!(fire_n1(&mut broadcast), [1, 2, 3]);
+
+        ack(&mut broadcast, &[1, 3]);
+        assert_eq!(broadcast.unacknowledged(), 1);
+        assert_eq!(fire_n1(&mut broadcast), [2]);
+
+        // acknowledged as part of what n1 has seen
+        let seen = vv(&[("n0", 2)]);
+        broadcast.ack(&"n1".to_owned(), &[], &seen).unwrap();
+        assert_eq!(broadcast.unacknowledged(), 0);
+        assert!(outbox(&broadcast, "n1").timer.is_none());
+    }
+
+    #[test]
+    fn items_received_from_a_neighbour_are_not_sent_back() {
+        let (mut broadcast, _events) = node(BroadcastConfig::default(), &["n1"]);
+        broadcast.broadcast('a').unwrap();
+        let items = vec![(id("n1", 1), 'b'), (id("n0", 1), 'a')];
+        let delivered = broadcast.receive(&"n1".to_owned(), items).unwrap();
+        assert_eq!(delivered, [(id("n1", 1), 'b')]);
+        assert_eq!(broadcast.delivered(), &vv(&[("n0", 1), ("n1", 1)]));
+        assert_eq!(broadcast.unacknowledged(), 0);
+    }
+
+    #[test]
+    fn items_past_a_gap_are_delivered_once() {
+        let (mut broadcast, _events) = node(BroadcastConfig::default(), &[]);
+        let from = "n1".to_owned();
+        let items = vec![(id("n2", 3), 'c'), (id("n2", 2), 'b')];
+        assert_eq!(broadcast.receive(&from, items.to_owned()).unwrap().len(), 2);
+        assert!(broadcast.receive(&from, items).unwrap().is_empty());
+        assert_eq!(broadcast.delivered(), &VersionVector::new());
+        assert_eq!(broadcast.out_of_order.len(), 2);
+
+        // filling the gap folds what follows it into the version vector
+        let items = vec![(id("n2", 1), 'a')];
+        assert_eq!(broadcast.receive(&from, items).unwrap().len(), 1);
+        assert_eq!(broadcast.delivered(), &vv(&[("n2", 3)]));
+        assert!(broadcast.out_of_order.is_empty());
+    }
+
+    #[test]
+    fn received_items_are_only_relayed_when_asked() {
+        for relay in [true, false] {
+            let config = BroadcastConfig {
+                relay,
+                ..Default::default()
+            };
+            let (mut broadcast, _events) = node(config, &["n1", "n2"]);
+            let items = vec![(id("n1", 1), 'a')];
+            broadcast.receive(&"n1".to_owned(), items).unwrap();
+            assert_eq!(broadcast.unacknowledged(), relay as usize);
+        }
+    }
+
+    #[test]
+    fn new_neighbours_are_sent_our_state_until_they_caught_up() {
+        let (mut broadcast, _events) = node(BroadcastConfig::default(), &["n1"]);
+        broadcast.broadcast('a').unwrap();
+        broadcast.broadcast('b').unwrap();
+        ack(&mut broadcast, &[1, 2]);
+        assert_eq!(broadcast.unacknowledged(), 0);
+
+        let neighbours = vec!["n1".to_owned(), "n2".to_owned()];
+        broadcast.set_neighbours(neighbours).unwrap();
+        broadcast.broadcast('c').unwrap();
+        let batch = fire(&mut broadcast, "n2");
+        assert!(batch.with_state);
+        assert_eq!(batch.items, [(id("n0", 3), 'c')]);
+        assert!(!fire(&mut broadcast, "n1").with_state);
+
+        let n2 = "n2".to_owned();
+        broadcast.ack(&n2, &[], &vv(&[("n0", 1)])).unwrap();
+        assert!(fire(&mut broadcast, "n2").with_state);
+        broadcast.ack(&n2, &[], &vv(&[("n0", 3)])).unwrap();
+        assert!(outbox(&broadcast, "n2").timer.is_none());
+    }
+
+    #[test]
+    fn removed_neighbours_are_no_longer_sent_anything() {
+        let (mut broadcast, events) = node(BroadcastConfig::default(), &["n1", "n2"]);
+        broadcast.broadcast('a').unwrap();
+        let timer = outbox(&broadcast, "n2").timer.unwrap();
+        broadcast.set_neighbours(vec!["n1".to_owned()]).unwrap();
+        assert!(!broadcast.outboxes.contains_key("n2"));
+        assert_eq!(broadcast.unacknowledged(), 1);
+        assert!(broadcast.on_timer(timer).unwrap().is_none());
+        let cancelled = events
+            .try_iter()
+            .any(|event| matches!(event, Event::Cancel(t) if t == timer));
+        assert!(cancelled);
+    }
+
+    #[test]
+    fn items_reach_every_node_through_relays_below_the_fanout() {
+        // a ring with a fanout of one: every item goes one way only, relayed by each node on it
+        let config = BroadcastConfig {
+            fanout: Some(1),
+            ..Default::default()
+        };
+        let names: Vec<ActorID> = (0..5).map(|i| format!("n{}", i)).collect();
+        let (mut nodes, _events): (Vec<Broadcast>, Vec<_>) = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let (tx, rx) = mpsc::channel();
+                let handle = Handle::new(name.to_owned(), tx);
+                let mut broadcast = ReliableBroadcast::new(handle, config.to_owned());
+                let prev = names[(i + names.len() - 1) % names.len()].to_owned();
+                let next = names[(i + 1) % names.len()].to_owned();
+                broadcast.set_neighbours(vec![prev, next]).unwrap();
+                (broadcast, rx)
+            })
+            .unzip();
+        nodes[0].broadcast('a').unwrap();
+        assert_eq!(nodes[0].unacknowledged(), 1);
+
+        for _ in 0..names.len() {
+            let batches: Vec<(usize, Batch<char>)> = nodes
+                .iter_mut()
+                .enumerate()
+                .flat_map(|(i, node)| {
+                    let timers: Vec<TimerId> = node.timers.keys().copied().collect();
+                    timers
+                        .into_iter()
+                        .filter_map(|timer| node.on_timer(timer).unwrap())
+                        .map(move |batch| (i, batch))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            for (src, batch) in batches {
+                let dest = names.iter().position(|n| *n == batch.dest).unwrap();
+                let ids: Vec<ItemID> = batch.items.iter().map(|(id, _)| id.to_owned()).collect();
+                nodes[dest].receive(&names[src], batch.items).unwrap();
+                let seen = nodes[dest].delivered().to_owned();
+                nodes[src].ack(&names[dest], &ids, &seen).unwrap();
+            }
+        }
+        for node in &nodes {
+            assert_eq!(node.delivered(), &vv(&[("n0", 1)]));
+            // sent to a single neighbour, n0 included
+            assert_eq!(node.outboxes.len(), 1);
+        }
+    }
+
+    #[test]
+    fn catching_up_counts_what_a_neighbour_saw_as_delivered() {
+        let (mut broadcast, _events) = node(BroadcastConfig::default(), &["n1"]);
+        let items = vec![(id("n2", 3), 'c')];
+        broadcast.receive(&"n1".to_owned(), items).unwrap();
+        assert!(broadcast.catch_up(&vv(&[("n1", 2), ("n2", 2)])));
+        assert_eq!(broadcast.delivered(), &vv(&[("n1", 2), ("n2", 3)]));
+        assert!(broadcast.out_of_order.is_empty());
+        assert!(!broadcast.catch_up(&vv(&[("n1", 1)])));
+
+        let items = vec![(id("n1", 2), 'b'), (id("n1", 3), 'd')];
+        let delivered = broadcast.receive(&"n1".to_owned(), items).unwrap();
+        assert_eq!(delivered, [(id("n1", 3), 'd')]);
+    }
+
+    #[test]
+    fn backoff_doubles_until_acknowledged() {
+        let config = BroadcastConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..Default::default()
+        };
+        let (mut broadcast, _events) = node(config, &["n1"]);
+        broadcast.broadcast('a').unwrap();
+        broadcast.broadcast('b').unwrap();
+        let mut backoffs = vec![outbox(&broadcast, "n1").backoff];
+        for _ in 0..3 {
+            fire_n1(&mut broadcast);
+            backoffs.push(outbox(&broadcast, "n1").backoff);
+        }
+        let expected = [100, 200, 350, 350].map(Duration::from_millis);
+        assert_eq!(backoffs, expected);
+
+        // progress resets the backoff
+        ack(&mut broadcast, &[1]);
+        assert_eq!(outbox(&broadcast, "n1").backoff, Duration::from_millis(100));
+        // acknowledging nothing new does not
+        fire_n1(&mut broadcast);
+        ack(&mut broadcast, &[1]);
+        assert_eq!(outbox(&broadcast, "n1").backoff, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn batches_are_capped_and_go_round_the_outbox() {
+        let config = BroadcastConfig {
+            max_batch: 2,
+            ..Default::default()
+        };
+        let (mut broadcast, _events) = node(config, &["n1"]);
+        for value in 'a'..='e' {
+            broadcast.broadcast(value).unwrap();
+        }
+        assert_eq!(fire_n1(&mut broadcast), [1, 2]);
+        assert_eq!(fire_n1(&mut broadcast), [3, 4]);
+        assert_eq!(fire_n1(&mut broadcast), [5, 1]);
+        ack(&mut broadcast, &[2, 3]);
+        assert_eq!(fire_n1(&mut broadcast), [4, 5]);
+    }
+}
@@ -0,0 +1,432 @@
+//! Logical clocks tracking causality between nodes.
+//!
+//! A [`VersionVector`] sums up what a replica has seen: for every origin, the number of its
+//! events, which must be numbered without gaps. A [`VectorClock`] timestamps events, so that
+//! two events can be told apart as ordered or concurrent.
+//!
+//! [`LamportClock`] and [`HybridLogicalClock`] give scalar timestamps instead, totally ordered
+//! and consistent with causality, the latter staying close to wall-clock time.
+use std::{
+    cmp::Ordering,
+    collections::{btree_map, BTreeMap},
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::actor::ActorID;
+
+/// Highest event seen from every node. Nodes which were never heard from are at 0.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<ActorID, u64>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.0.get(node_id).copied().unwrap_or_default()
+    }
+
+    /// Whether event `seq` of `node_id` was seen
+    pub fn contains(&self, node_id: &str, seq: u64) -> bool {
+        seq <= self.get(node_id)
+    }
+
+    /// Count a new event of `node_id`, returning its number
+    pub fn increment(&mut self, node_id: &str) -> u64 {
+        let seq = self.0.entry(node_id.to_owned()).or_default();
+        *seq += 1;
+        *seq
+    }
+
+    /// Record event `seq` of `node_id` if it directly follows the ones seen, so as to leave no gap.
+    /// Returns whether it did.
+    pub fn observe(&mut self, node_id: &str, seq: u64) -> bool {
+        if seq == self.get(node_id) + 1 {
+            self.0.insert(node_id.to_owned(), seq);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take in everything `other` has seen: the pointwise maximum
+    pub fn merge(&mut self, other: &Self) {
+        for (node_id, seq) in &other.0 {
+            let ours = self.0.entry(node_id.to_owned()).or_default();
+            *ours = (*ours).max(*seq);
+        }
+    }
+
+    /// What both sides have seen: the pointwise minimum
+    pub fn meet(&self, other: &Self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .map(|(node_id, seq)| (node_id.to_owned(), (*seq).min(other.get(node_id))))
+                .filter(|(_, seq)| *seq > 0)
+                .collect(),
+        )
+    }
+
+    /// Events seen by `other` but not by us, by origin
+    pub fn missing(&self, other: &Self) -> Vec<(ActorID, RangeInclusive<u64>)> {
+        other
+            .0
+            .iter()
+            .filter(|(node_id, seq)| **seq > self.get(node_id))
+            .map(|(node_id, seq)| (node_id.to_owned(), self.get(node_id) + 1..=*seq))
+            .collect()
+    }
+
+    /// Whether we have seen everything `other` has
+    pub fn dominates(&self, other: &Self) -> bool {
+        matches!(
+            self.partial_cmp(other),
+            Some(Ordering::Greater | Ordering::Equal)
+        )
+    }
+
+    /// Whether each side has seen something the other has not
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, ActorID, u64> {
+        self.0.iter()
+    }
+}
+
+/// Nodes at 0 may or may not be listed, so vectors are compared by value rather than by map
+impl PartialEq for VersionVector {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for VersionVector {}
+
+/// Partial order of what was seen: `None` when the vectors are concurrent
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node_id in self.0.keys().chain(other.0.keys()) {
+            match (ordering, self.get(node_id).cmp(&other.get(node_id))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, cmp) => ordering = cmp,
+                (current, cmp) if current != cmp => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+impl FromIterator<(ActorID, u64)> for VersionVector {
+    fn from_iter<I: IntoIterator<Item = (ActorID, u64)>>(iter: I) -> Self {
+        let mut vv = Self::new();
+        for (node_id, seq) in iter {
+            let ours = vv.0.entry(node_id).or_default();
+            *ours = (*ours).max(seq);
+        }
+        vv
+    }
+}
+
+/// Timestamp of an event. An event happened before another if its clock is smaller,
+/// and the two are concurrent if neither is.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(VersionVector);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.0.get(node_id)
+    }
+
+    /// Count a local event of `node_id`, such as sending a message
+    pub fn increment(&mut self, node_id: &str) -> &Self {
+        self.0.increment(node_id);
+        self
+    }
+
+    /// Take in the clock of another event, without counting a new one
+    pub fn merge(&mut self, other: &Self) {
+        self.0.merge(&other.0);
+    }
+
+    /// Count the receipt by `node_id` of a message stamped with `other`
+    pub fn receive(&mut self, node_id: &str, other: &Self) -> &Self {
+        self.merge(other);
+        self.increment(node_id)
+    }
+
+    pub fn happened_before(&self, other: &Self) -> bool {
+        self < other
+    }
+
+    /// Whether every event before `other` is also before us
+    pub fn dominates(&self, other: &Self) -> bool {
+        self.0.dominates(&other.0)
+    }
+
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.0.concurrent(&other.0)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, ActorID, u64> {
+        self.0.iter()
+    }
+}
+
+/// Counter which orders events consistently with causality: an event sent before another
+/// was received always has a smaller time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LamportClock(u64);
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time of the latest event
+    pub fn time(&self) -> u64 {
+        self.0
+    }
+
+    /// Count a local event, such as sending a message, returning its time
+    pub fn tick(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    /// Count the receipt of a message sent at `remote`, returning the time of the receipt
+    pub fn receive(&mut self, remote: u64) -> u64 {
+        self.0 = self.0.max(remote) + 1;
+        self.0
+    }
+}
+
+/// Timestamp of a [`HybridLogicalClock`]. Ordered by wall-clock time, then by the logical
+/// counter, which orders events happening within the same millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct HlcTimestamp {
+    /// Milliseconds since the UNIX epoch
+    pub wall: u64,
+    pub logical: u64,
+}
+
+/// Clock whose timestamps are consistent with causality like a [`LamportClock`],
+/// while staying as close as possible to wall-clock time, even if the clocks of nodes drift
+/// apart or go backwards.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HybridLogicalClock {
+    latest: HlcTimestamp,
+}
+
+impl HybridLogicalClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamp of the latest event
+    pub fn latest(&self) -> HlcTimestamp {
+        self.latest
+    }
+
+    /// Timestamp a local event, such as sending a message
+    pub fn now(&mut self) -> HlcTimestamp {
+        self.now_at(unix_millis(SystemTime::now()))
+    }
+
+    /// Like [`HybridLogicalClock::now`], with the wall-clock time given in milliseconds since the UNIX epoch
+    pub fn now_at(&mut self, physical: u64) -> HlcTimestamp {
+        self.latest = if physical > self.latest.wall {
+            HlcTimestamp {
+                wall: physical,
+                logical: 0,
+            }
+        } else {
+            HlcTimestamp {
+                wall: self.latest.wall,
+                logical: self.latest.logical + 1,
+            }
+        };
+        self.latest
+    }
+
+    /// Take in the timestamp of a received message, returning the timestamp of the receipt
+    pub fn receive(&mut self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.receive_at(remote, unix_millis(SystemTime::now()))
+    }
+
+    /// Like [`HybridLogicalClock::receive`], with the wall-clock time given in milliseconds since the UNIX epoch
+    pub fn receive_at(&mut self, remote: HlcTimestamp, physical: u64) -> HlcTimestamp {
+        let ours = self.latest;
+        let wall = physical.max(ours.wall).max(remote.wall);
+        let logical = match (wall == ours.wall, wall == remote.wall) {
+            (true, true) => ours.logical.max(remote.logical) + 1,
+            (true, false) => ours.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        self.latest = HlcTimestamp { wall, logical };
+        self.latest
+    }
+}
+
+/// Milliseconds elapsed between the UNIX epoch and `time`, 0 before it
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vv(entries: &[(&str, u64)]) -> VersionVector {
+        entries
+            .iter()
+            .map(|(node_id, seq)| (node_id.to_string(), *seq))
+            .collect()
+    }
+
+    #[test]
+    fn version_vectors_are_partially_ordered() {
+        let a = vv(&[("n0", 2), ("n1", 1)]);
+        let b = vv(&[("n0", 2), ("n1", 3)]);
+        let c = vv(&[("n0", 3)]);
+        assert!(b.dominates(&a) && !a.dominates(&b));
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Less));
+        assert!(a.concurrent(&c) && b.concurrent(&c));
+        assert!(!a.concurrent(&a) && a.dominates(&a));
+        // nodes at 0 may or may not be listed
+        assert_eq!(vv(&[("n0", 2), ("n2", 0)]), vv(&[("n0", 2)]));
+    }
+
+    #[test]
+    fn merge_is_the_pointwise_maximum() {
+        let mut a = vv(&[("n0", 2), ("n1", 1)]);
+        a.merge(&vv(&[("n1", 3), ("n2", 1)]));
+        assert_eq!(a, vv(&[("n0", 2), ("n1", 3), ("n2", 1)]));
+    }
+
+    #[test]
+    fn events_are_observed_without_gaps() {
+        let mut a = VersionVector::new();
+        assert_eq!(a.increment("n0"), 1);
+        assert!(!a.observe("n1", 2));
+        assert!(!a.contains("n1", 1));
+        assert!(a.observe("n1", 1));
+        assert!(a.observe("n1", 2));
+        assert!(!a.observe("n1", 2));
+        assert!(a.contains("n1", 2) && !a.contains("n1", 3));
+        assert_eq!(a.iter().count(), 2);
+    }
+
+    #[test]
+    fn meet_is_the_pointwise_minimum() {
+        let a = vv(&[("n0", 2), ("n1", 4)]);
+        let b = vv(&[("n1", 3), ("n2", 1)]);
+        assert_eq!(a.meet(&b), vv(&[("n1", 3)]));
+        assert_eq!(a.meet(&b), b.meet(&a));
+        assert!(a.dominates(&a.meet(&b)) && b.dominates(&a.meet(&b)));
+    }
+
+    #[test]
+    fn missing_ranges_are_what_the_other_side_saw_past_us() {
+        let a = vv(&[("n0", 2), ("n1", 4)]);
+        let b = vv(&[("n0", 5), ("n1", 1), ("n2", 2)]);
+        assert_eq!(
+            a.missing(&b),
+            [("n0".to_owned(), 3..=5), ("n2".to_owned(), 1..=2)]
+        );
+        assert_eq!(b.missing(&a), [("n1".to_owned(), 2..=4)]);
+        assert!(a.missing(&a).is_empty());
+        let mut merged = a.to_owned();
+        merged.merge(&b);
+        assert!(merged.missing(&a).is_empty() && merged.missing(&b).is_empty());
+    }
+
+    #[test]
+    fn vector_clocks_order_causally_related_events() {
+        let mut n0 = VectorClock::new();
+        let mut n1 = VectorClock::new();
+        let sent = n0.increment("n0").to_owned();
+        let local = n1.increment("n1").to_owned();
+        assert!(sent.concurrent(&local));
+
+        let received = n1.receive("n1", &sent).to_owned();
+        assert!(sent.happened_before(&received) && local.happened_before(&received));
+        assert!(received.dominates(&sent) && !sent.dominates(&received));
+        assert_eq!((received.get("n0"), received.get("n1")), (1, 2));
+
+        // merging counts no new event
+        n0.merge(&received);
+        assert_eq!(n0, received);
+        assert!(!n0.happened_before(&received) && !n0.concurrent(&received));
+        assert_eq!(n0.iter().count(), 2);
+    }
+
+    #[test]
+    fn vector_clocks_serialize_as_a_map() {
+        let mut clock = VectorClock::new();
+        clock.increment("n0");
+        clock.increment("n1");
+        let json = serde_json::to_value(&clock).unwrap();
+        assert_eq!(json, serde_json::json!({"n0": 1, "n1": 1}));
+        let back: VectorClock = serde_json::from_value(json).unwrap();
+        assert_eq!(back, clock);
+    }
+
+    #[test]
+    fn lamport_clocks_move_past_received_times() {
+        let mut clock = LamportClock::new();
+        assert_eq!(clock.tick(), 1);
+        assert_eq!(clock.receive(5), 6);
+        assert_eq!(clock.receive(2), 7);
+        assert_eq!(clock.time(), 7);
+    }
+
+    fn hlc(wall: u64, logical: u64) -> HlcTimestamp {
+        HlcTimestamp { wall, logical }
+    }
+
+    #[test]
+    fn hybrid_clocks_follow_the_wall_clock() {
+        let mut clock = HybridLogicalClock::new();
+        assert_eq!(clock.now_at(10), hlc(10, 0));
+        // the wall clock stands still, then goes backwards
+        assert_eq!(clock.now_at(10), hlc(10, 1));
+        assert_eq!(clock.now_at(8), hlc(10, 2));
+        assert_eq!(clock.now_at(12), hlc(12, 0));
+        assert_eq!(clock.latest(), hlc(12, 0));
+    }
+
+    #[test]
+    fn hybrid_clocks_move_past_received_timestamps() {
+        let mut clock = HybridLogicalClock::new();
+        clock.now_at(10);
+        // a remote clock ahead of ours
+        assert_eq!(clock.receive_at(hlc(20, 3), 11), hlc(20, 4));
+        assert_eq!(clock.now_at(12), hlc(20, 5));
+        // a remote clock at the same time as ours
+        assert_eq!(clock.receive_at(hlc(20, 7), 12), hlc(20, 8));
+        // a remote clock behind ours
+        assert_eq!(clock.receive_at(hlc(15, 0), 12), hlc(20, 9));
+        // the wall clock catches up
+        assert_eq!(clock.receive_at(hlc(15, 0), 25), hlc(25, 0));
+    }
+}
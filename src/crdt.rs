@@ -1,152 +1,1139 @@
 use crate::{
-    actor::ActorID,
-    message::{Message, MessageID},
+    actor::{Actor, ActorID},
+    broadcast::{Batch, BroadcastConfig, ItemID, ReliableBroadcast},
+    clock::{HlcTimestamp, VersionVector},
+    errors::Error,
+    message::{Body, Message},
+    runtime::{Handle, Runtime},
+    timer::TimerId,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use std::{
-    collections::{HashMap, HashSet},
-    sync::mpsc::Sender,
-    thread,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     time::Duration,
 };
 
-type UniqueMessageID = (ActorID, MessageID);
-
-#[derive(Default)]
-pub struct CrdtBase<T> {
-    pub node_id: Option<ActorID>,
-    pub peers: Vec<ActorID>,
-    pub messages: Vec<(UniqueMessageID, T)>,
-    pub known: HashMap<ActorID, HashSet<UniqueMessageID>>,
-}
-
 /// T is the individual message type
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum Payload<T> {
+    /// The g-set workload names the operation `element`
     Add {
-        msg_id: MessageID,
+        #[serde(alias = "element")]
         delta: T,
     },
-    AddOk {
-        in_reply_to: MessageID,
-    },
-    Read {
-        msg_id: MessageID,
-    },
+    AddOk,
+    Read,
     ReadOk {
-        in_reply_to: MessageID,
         value: Value,
     },
-    StartGossip,
-    Gossip {
-        payload: Vec<(UniqueMessageID, T)>,
+    /// Full state of a state-based [`Crdt`], to merge into ours
+    Merge {
+        state: Value,
+    },
+    /// Join of the deltas up to sequence number `seq`, or a full state, sent by a [`DeltaCrdtActor`]
+    Delta {
+        delta: Value,
+        seq: u64,
+    },
+    DeltaOk {
+        seq: u64,
+    },
+    /// Deltas of local operations, sent by a [`BroadcastCrdtActor`], along with its state and
+    /// the deltas it has seen when the receiver is to catch up with them
+    Deltas {
+        deltas: Vec<(ItemID, Value)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<(Value, VersionVector)>,
     },
-    GossipOk {
-        seen: HashSet<UniqueMessageID>,
+    /// Acknowledges the deltas received, and every delta the sender has seen
+    DeltasOk {
+        ids: Vec<ItemID>,
+        seen: VersionVector,
     },
 }
 
-pub enum CrdtMessageResponse<T> {
-    Responses(Vec<Message<Payload<T>>>),
-    ReadRequest(MessageID),
+/// A state-based CRDT: replicas converge by periodically merging each other's full state,
+/// so the amount of data kept and gossiped does not grow with the number of operations.
+pub trait Crdt: Default + Serialize + DeserializeOwned {
+    /// Operation sent by clients in `add` requests
+    type Op: Serialize + DeserializeOwned + Clone + Send;
+
+    /// Apply an operation received by `node_id` at time `now` of its clock (see
+    /// [`Handle::timestamp`]), returning its delta: a state which has the same effect as the
+    /// operation when merged into any replica. Kept as small as possible.
+    fn apply_local(&mut self, node_id: &str, now: HlcTimestamp, op: Self::Op) -> Self;
+
+    /// Merge the state of another replica into ours.
+    /// Must be commutative, associative and idempotent.
+    fn merge(&mut self, other: &Self);
+
+    /// Value answered to `read` requests
+    fn value(&self) -> Value;
+}
+
+/// Grow-only counter: every node counts its own increments, the value is their sum
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<ActorID, u64>,
+}
+
+impl Crdt for GCounter {
+    type Op = u64;
+
+    fn apply_local(&mut self, node_id: &str, _now: HlcTimestamp, op: u64) -> Self {
+        let count = self.counts.entry(node_id.to_owned()).or_default();
+        *count += op;
+        Self {
+            counts: BTreeMap::from([(node_id.to_owned(), *count)]),
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (node_id, count) in &other.counts {
+            let ours = self.counts.entry(node_id.to_owned()).or_default();
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    fn value(&self) -> Value {
+        self.counts.values().sum::<u64>().into()
+    }
+}
+
+/// Counter supporting decrements: a pair of grow-only counters, one per sign
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct PnCounter {
+    positive: GCounter,
+    negative: GCounter,
+}
+
+impl Crdt for PnCounter {
+    type Op = i64;
+
+    fn apply_local(&mut self, node_id: &str, now: HlcTimestamp, op: i64) -> Self {
+        if op >= 0 {
+            Self {
+                positive: self.positive.apply_local(node_id, now, op.unsigned_abs()),
+                negative: Default::default(),
+            }
+        } else {
+            Self {
+                positive: Default::default(),
+                negative: self.negative.apply_local(node_id, now, op.unsigned_abs()),
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.positive.merge(&other.positive);
+        self.negative.merge(&other.negative);
+    }
+
+    fn value(&self) -> Value {
+        let positive: u64 = self.positive.counts.values().sum();
+        let negative: u64 = self.negative.counts.values().sum();
+        (positive as i64 - negative as i64).into()
+    }
+}
+
+/// Grow-only set. Elements are deduplicated by value, however many times they are added.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned + Send> Crdt for GSet<T> {
+    type Op = T;
+
+    fn apply_local(&mut self, _node_id: &str, _now: HlcTimestamp, op: T) -> Self {
+        self.elements.insert(op.to_owned());
+        Self {
+            elements: BTreeSet::from([op]),
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn value(&self) -> Value {
+        serde_json::to_value(&self.elements).expect("expected set to marshall to json")
+    }
+}
+
+/// Uniquely identifies an addition to an [`OrSet`]: the node which made it, and its count of additions
+type Dot = (ActorID, u64);
+
+/// Operations on an [`OrSet`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrSetOp<T> {
+    Add(T),
+    Remove(T),
+}
+
+/// Observed-remove set, where a concurrent add and remove of an element resolve to the add.
+///
+/// Every addition is tagged with a unique [`Dot`]. Removing an element drops the dots we have
+/// observed for it, and `context` remembers that they existed, so that merging does not bring
+/// them back. No tombstone is kept.
+///
+/// No Maelstrom workload removes elements, so no binary serves it; the tests below exercise it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Ord + Deserialize<'de>"))]
+pub struct OrSet<T: Ord> {
+    #[serde(with = "as_pairs")]
+    entries: BTreeMap<T, BTreeSet<Dot>>,
+    context: DotContext,
+}
+
+/// The dots an [`OrSet`] has seen: every dot up to `clock[node]` from each node, plus the
+/// `cloud` of dots seen out of order. A delta only carries the dots it adds or removes, so
+/// deltas can be merged in any order.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct DotContext {
+    clock: VersionVector,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    cloud: BTreeSet<Dot>,
+}
+
+impl DotContext {
+    /// Context of a delta which saw exactly `dots`
+    fn of(dots: BTreeSet<Dot>) -> Self {
+        let mut context = Self {
+            clock: VersionVector::new(),
+            cloud: dots,
+        };
+        context.compact();
+        context
+    }
+
+    /// Whether `dot` was seen, i.e. it is either live or was removed
+    fn covers(&self, dot: &Dot) -> bool {
+        self.clock.contains(&dot.0, dot.1) || self.cloud.contains(dot)
+    }
+
+    /// Allocate the next dot of `node_id`
+    fn next_dot(&mut self, node_id: &str) -> Dot {
+        (node_id.to_owned(), self.clock.increment(node_id))
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.clock.merge(&other.clock);
+        self.cloud.extend(other.cloud.iter().cloned());
+        self.compact();
+    }
+
+    /// Fold the dots of the cloud which extend the clock into it.
+    /// Dots are visited in ascending order, so a run of consecutive dots is folded in one pass.
+    fn compact(&mut self) {
+        let clock = &mut self.clock;
+        self.cloud.retain(|(node_id, counter)| {
+            !clock.observe(node_id, *counter) && !clock.contains(node_id, *counter)
+        });
+    }
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            context: Default::default(),
+        }
+    }
+}
+
+impl<T: Ord> OrSet<T> {
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries.contains_key(element)
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned + Send> Crdt for OrSet<T> {
+    type Op = OrSetOp<T>;
+
+    fn apply_local(&mut self, node_id: &str, _now: HlcTimestamp, op: OrSetOp<T>) -> Self {
+        match op {
+            OrSetOp::Add(element) => {
+                let dot = self.context.next_dot(node_id);
+                self.entries
+                    .entry(element.to_owned())
+                    .or_default()
+                    .insert(dot.to_owned());
+                Self {
+                    entries: BTreeMap::from([(element, BTreeSet::from([dot.to_owned()]))]),
+                    context: DotContext::of(BTreeSet::from([dot])),
+                }
+            }
+            OrSetOp::Remove(element) => Self {
+                entries: BTreeMap::new(),
+                context: DotContext::of(self.entries.remove(&element).unwrap_or_default()),
+            },
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        let elements: BTreeSet<T> = self
+            .entries
+            .keys()
+            .chain(other.entries.keys())
+            .cloned()
+            .collect();
+        let empty = BTreeSet::new();
+        for element in elements {
+            let ours = self.entries.get(&element).unwrap_or(&empty);
+            let theirs = other.entries.get(&element).unwrap_or(&empty);
+            // keep the dots both sides have, and the ones the other side has not removed yet
+            let dots: BTreeSet<Dot> = ours
+                .intersection(theirs)
+                .chain(ours.difference(theirs).filter(|d| !other.context.covers(d)))
+                .chain(theirs.difference(ours).filter(|d| !self.context.covers(d)))
+                .cloned()
+                .collect();
+            if dots.is_empty() {
+                self.entries.remove(&element);
+            } else {
+                self.entries.insert(element, dots);
+            }
+        }
+        self.context.merge(&other.context);
+    }
+
+    fn value(&self) -> Value {
+        serde_json::to_value(self.entries.keys().collect::<Vec<_>>())
+            .expect("expected set to marshall to json")
+    }
+}
+
+/// JSON objects only have string keys, so maps keyed by arbitrary values are written as a list of pairs
+mod as_pairs {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K: Serialize, V: Serialize, S: Serializer>(
+        map: &BTreeMap<K, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Ord + Deserialize<'de>,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Vec::<(K, V)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+/// Timestamp of a write: the time of a [`HybridLogicalClock`], so that successive writes are
+/// ordered even when the clock stands still or goes backwards. Ties are broken by node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HybridTimestamp {
+    #[serde(flatten)]
+    pub time: HlcTimestamp,
+    pub node_id: ActorID,
+}
+
+impl HybridTimestamp {
+    /// A timestamp for `node_id` greater than `self`: `now`, or the time right after `self` when
+    /// `now` is not past it
+    pub fn next(&self, node_id: &str, now: HlcTimestamp) -> Self {
+        let time = if now > self.time {
+            now
+        } else {
+            HlcTimestamp {
+                wall: self.time.wall,
+                logical: self.time.logical + 1,
+            }
+        };
+        Self {
+            time,
+            node_id: node_id.to_owned(),
+        }
+    }
+}
+
+/// Register holding the value of the latest write, by [`HybridTimestamp`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    timestamp: HybridTimestamp,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: Default::default(),
+        }
+    }
+}
+
+impl<T> LwwRegister<T> {
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn timestamp(&self) -> &HybridTimestamp {
+        &self.timestamp
+    }
 }
 
-impl<T: Send + Clone + 'static> CrdtBase<T> {
+impl<T: Clone + Serialize + DeserializeOwned + Send> Crdt for LwwRegister<T> {
+    type Op = T;
+
+    fn apply_local(&mut self, node_id: &str, now: HlcTimestamp, op: T) -> Self {
+        self.timestamp = self.timestamp.next(node_id, now);
+        self.value = Some(op);
+        self.to_owned()
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if other.timestamp > self.timestamp {
+            self.timestamp = other.timestamp.to_owned();
+            self.value = other.value.to_owned();
+        }
+    }
+
+    fn value(&self) -> Value {
+        serde_json::to_value(&self.value).expect("expected register to marshall to json")
+    }
+}
+
+/// How often [`CrdtActor`] sends its state to its peers
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(150);
+
+/// Actor serving the `add`/`read` workloads with any state-based [`Crdt`]
+#[derive(Default)]
+pub struct CrdtActor<C: Crdt> {
+    node_id: Option<ActorID>,
+    peers: Vec<ActorID>,
+    handle: Option<Handle<Body<Payload<C::Op>>>>,
+    state: C,
+}
+
+impl<C: Crdt> CrdtActor<C> {
     #[inline(always)]
     pub fn node_id(&self) -> String {
         self.node_id.as_ref().unwrap().to_owned()
     }
 
-    pub fn spawn_gossip_thread(&mut self, tx: Sender<Message<Payload<T>>>, interval: Duration) {
+    /// Current state of this replica
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Time of a local operation on the clock of the node
+    fn timestamp(&self) -> Result<HlcTimestamp, Error> {
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        Ok(handle.timestamp())
+    }
+}
+
+impl<C: Crdt> Actor for CrdtActor<C> {
+    type MessagePayload = Body<Payload<C::Op>>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: String,
+        peers: Vec<String>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.peers = peers.into_iter().filter(|p| *p != node_id).collect();
+        self.node_id = Some(node_id);
+        handle.schedule_every(GOSSIP_INTERVAL)?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Add { delta } => {
+                let node_id = self.node_id();
+                let now = self.timestamp()?;
+                self.state.apply_local(&node_id, now, delta.to_owned());
+                Ok(vec![message.reply(Payload::AddOk)])
+            }
+            Payload::Read => Ok(vec![message.reply(Payload::ReadOk {
+                value: self.state.value(),
+            })]),
+            Payload::Merge { state } => {
+                let other: C = serde_json::from_value(state.to_owned())
+                    .map_err(|_| Error::MalformedRequest)?;
+                self.state.merge(&other);
+                Ok(vec![])
+            }
+            Payload::AddOk
+            | Payload::ReadOk { .. }
+            | Payload::Delta { .. }
+            | Payload::DeltaOk { .. }
+            | Payload::Deltas { .. }
+            | Payload::DeltasOk { .. } => Ok(vec![]),
+        }
+    }
+
+    fn on_timer(&mut self, _timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let state = serde_json::to_value(&self.state).map_err(|_| Error::Crash)?;
         let node_id = self.node_id();
-        thread::spawn(move || loop {
-            let node_id = node_id.clone();
-            std::thread::sleep(interval);
-            match tx.send(Message {
-                src: node_id.clone(),
-                dest: node_id.clone(),
-                body: Payload::StartGossip,
-            }) {
-                Ok(_) => continue,
-                Err(e) => {
-                    eprintln!("error while sending gossip signal: {}", e);
-                    break;
+        Ok(self
+            .peers
+            .iter()
+            .map(|peer| Message {
+                src: node_id.to_owned(),
+                dest: peer.to_owned(),
+                body: Body::new(Payload::Merge {
+                    state: state.to_owned(),
+                }),
+            })
+            .collect())
+    }
+}
+
+/// How many deltas [`DeltaCrdtActor`] keeps for the peers which have not acknowledged them.
+/// A peer lagging further behind is sent our full state instead.
+pub const DELTA_BUFFER_SIZE: usize = 256;
+
+/// Actor serving the `add`/`read` workloads with any [`Crdt`], gossiping deltas instead of full states.
+///
+/// Every local operation yields a delta, numbered with a sequence number and kept in a bounded
+/// buffer until every peer has acknowledged it. Each gossip round sends a peer the join of the
+/// deltas it has not acknowledged yet, or our full state when some of them were already evicted
+/// from the buffer. Only the deltas of local operations are buffered: every node gossips with every
+/// other node directly.
+#[derive(Default)]
+pub struct DeltaCrdtActor<C: Crdt> {
+    node_id: Option<ActorID>,
+    peers: Vec<ActorID>,
+    handle: Option<Handle<Body<Payload<C::Op>>>>,
+    state: C,
+    /// Latest deltas of local operations, with their sequence number
+    deltas: VecDeque<(u64, C)>,
+    /// Sequence number of the latest local operation
+    seq: u64,
+    /// Highest sequence number acknowledged by each peer
+    acked: HashMap<ActorID, u64>,
+}
+
+impl<C: Crdt> DeltaCrdtActor<C> {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Current state of this replica
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Time of a local operation on the clock of the node
+    fn timestamp(&self) -> Result<HlcTimestamp, Error> {
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        Ok(handle.timestamp())
+    }
+
+    /// Drop the deltas which every peer has acknowledged
+    fn prune(&mut self) {
+        let acked_by_all = self
+            .peers
+            .iter()
+            .map(|peer| self.acked.get(peer).copied().unwrap_or_default())
+            .min()
+            .unwrap_or(self.seq);
+        while self
+            .deltas
+            .front()
+            .is_some_and(|(seq, _)| *seq <= acked_by_all)
+        {
+            self.deltas.pop_front();
+        }
+    }
+}
+
+impl<C: Crdt> Actor for DeltaCrdtActor<C> {
+    type MessagePayload = Body<Payload<C::Op>>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: String,
+        peers: Vec<String>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.peers = peers.into_iter().filter(|p| *p != node_id).collect();
+        self.node_id = Some(node_id);
+        handle.schedule_every(GOSSIP_INTERVAL)?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Add { delta } => {
+                let node_id = self.node_id();
+                let now = self.timestamp()?;
+                let delta = self.state.apply_local(&node_id, now, delta.to_owned());
+                self.seq += 1;
+                self.deltas.push_back((self.seq, delta));
+                if self.deltas.len() > DELTA_BUFFER_SIZE {
+                    self.deltas.pop_front();
                 }
+                Ok(vec![message.reply(Payload::AddOk)])
             }
-        });
+            Payload::Read => Ok(vec![message.reply(Payload::ReadOk {
+                value: self.state.value(),
+            })]),
+            Payload::Delta { delta, seq } => {
+                let other: C = serde_json::from_value(delta.to_owned())
+                    .map_err(|_| Error::MalformedRequest)?;
+                self.state.merge(&other);
+                Ok(vec![message.reply(Payload::DeltaOk { seq: *seq })])
+            }
+            Payload::DeltaOk { seq } => {
+                let acked = self.acked.entry(message.src.to_owned()).or_default();
+                *acked = (*acked).max(*seq);
+                self.prune();
+                Ok(vec![])
+            }
+            Payload::Merge { state } => {
+                let other: C = serde_json::from_value(state.to_owned())
+                    .map_err(|_| Error::MalformedRequest)?;
+                self.state.merge(&other);
+                Ok(vec![])
+            }
+            Payload::AddOk
+            | Payload::ReadOk { .. }
+            | Payload::Deltas { .. }
+            | Payload::DeltasOk { .. } => Ok(vec![]),
+        }
+    }
+
+    fn on_timer(&mut self, _timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let node_id = self.node_id();
+        let mut messages = vec![];
+        for peer in &self.peers {
+            let acked = self.acked.get(peer).copied().unwrap_or_default();
+            if acked >= self.seq {
+                continue;
+            }
+            // the buffer no longer holds every delta the peer is missing
+            let truncated = self
+                .deltas
+                .front()
+                .is_none_or(|(oldest, _)| *oldest > acked + 1);
+            let delta = if truncated {
+                serde_json::to_value(&self.state)
+            } else {
+                let mut group = C::default();
+                self.deltas
+                    .iter()
+                    .filter(|(seq, _)| *seq > acked)
+                    .for_each(|(_, delta)| group.merge(delta));
+                serde_json::to_value(&group)
+            }
+            .map_err(|_| Error::Crash)?;
+            messages.push(Message {
+                src: node_id.to_owned(),
+                dest: peer.to_owned(),
+                body: Body::new(Payload::Delta {
+                    delta,
+                    seq: self.seq,
+                }),
+            });
+        }
+        Ok(messages)
     }
+}
+
+/// Deltas of a [`BroadcastCrdtActor`]
+type DeltaBroadcast<C> = ReliableBroadcast<Value, Body<Payload<<C as Crdt>::Op>>>;
+
+/// Actor serving the `add`/`read` workloads with any [`Crdt`], sending the delta of every local
+/// operation to every other node through a [`ReliableBroadcast`].
+///
+/// Each delta is sent on its own, batched with the others and retransmitted until acknowledged,
+/// so nothing is sent while there are no new operations. A delta is merged into the state as
+/// soon as it is delivered, and dropped once every peer acknowledged it: what was delivered is
+/// only remembered as a [`VersionVector`], so memory does not grow with the number of operations.
+#[derive(Default)]
+pub struct BroadcastCrdtActor<C: Crdt> {
+    node_id: Option<ActorID>,
+    handle: Option<Handle<Body<Payload<C::Op>>>>,
+    broadcast: Option<DeltaBroadcast<C>>,
+    state: C,
+}
 
-    pub fn process_crdt_payload(
+impl<C: Crdt> BroadcastCrdtActor<C> {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Current state of this replica
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Time of a local operation on the clock of the node
+    fn timestamp(&self) -> Result<HlcTimestamp, Error> {
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        Ok(handle.timestamp())
+    }
+
+    fn broadcast(&mut self) -> Result<&mut DeltaBroadcast<C>, Error> {
+        self.broadcast.as_mut().ok_or(Error::TemporarilyUnavailable)
+    }
+}
+
+impl<C: Crdt> Actor for BroadcastCrdtActor<C> {
+    type MessagePayload = Body<Payload<C::Op>>;
+
+    fn init(
         &mut self,
-        message: &Message<Payload<T>>,
-    ) -> CrdtMessageResponse<T> {
-        match &message.body {
-            Payload::Add { msg_id, delta: value } => {
-                let unique_id: UniqueMessageID = (message.src.to_string(), *msg_id);
-                let our_known = self.known.entry(self.node_id()).or_default();
-                self.messages.push((unique_id.to_owned(), value.to_owned()));
-                our_known.insert(unique_id);
-                CrdtMessageResponse::Responses(vec![Message::new_reply_to(
-                    message,
-                    Payload::AddOk { in_reply_to: *msg_id },
-                )])
-            }
-            Payload::Read { msg_id } => CrdtMessageResponse::ReadRequest(*msg_id),
-            Payload::StartGossip => {
+        handle: Handle<Self::MessagePayload>,
+        node_id: String,
+        peers: Vec<String>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        // every node sends its deltas to every other one itself
+        let config = BroadcastConfig {
+            relay: false,
+            ..Default::default()
+        };
+        let mut broadcast = ReliableBroadcast::new(handle.clone(), config);
+        broadcast.set_neighbours(peers.into_iter().filter(|p| *p != node_id).collect())?;
+        self.node_id = Some(node_id);
+        self.handle = Some(handle);
+        self.broadcast = Some(broadcast);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            Payload::Add { delta } => {
                 let node_id = self.node_id();
-                let responses = self
-                    .peers
+                let now = self.timestamp()?;
+                let delta = self.state.apply_local(&node_id, now, delta.to_owned());
+                let delta = serde_json::to_value(&delta).map_err(|_| Error::Crash)?;
+                self.broadcast()?.broadcast(delta)?;
+                Ok(vec![message.reply(Payload::AddOk)])
+            }
+            Payload::Read => Ok(vec![message.reply(Payload::ReadOk {
+                value: self.state.value(),
+            })]),
+            Payload::Deltas { deltas, state } => {
+                let parse = |value: &Value| -> Result<C, Error> {
+                    serde_json::from_value(value.to_owned()).map_err(|_| Error::MalformedRequest)
+                };
+                let mut parsed = deltas
                     .iter()
-                    .filter_map(|peer| {
-                        let known = self.known.entry(peer.to_owned()).or_default();
-                        let msgs_to_send: Vec<(UniqueMessageID, T)> = self
-                            .messages
-                            .iter()
-                            .cloned()
-                            .filter(|(msg_id, _)| !known.contains(msg_id))
-                            .collect();
-                        if msgs_to_send.is_empty() {
-                            None
-                        } else {
-                            Some(Message {
-                                src: node_id.clone(),
-                                dest: peer.to_owned(),
-                                body: Payload::Gossip {
-                                    payload: msgs_to_send,
-                                },
-                            })
-                        }
-                    })
-                    .collect();
-                CrdtMessageResponse::Responses(responses)
-            }
-            Payload::Gossip { payload } => {
-                let our_id = self.node_id();
-                let our_known = self.known.entry(our_id).or_default();
-                for (msg_id, value) in payload {
-                    if !our_known.contains(msg_id) {
-                        self.messages.push((msg_id.to_owned(), value.to_owned()));
-                        our_known.insert(msg_id.to_owned());
+                    .map(|(id, delta)| Ok((id.to_owned(), parse(delta)?)))
+                    .collect::<Result<BTreeMap<ItemID, C>, Error>>()?;
+                if let Some((state, seen)) = state {
+                    let other = parse(state)?;
+                    if self.broadcast()?.catch_up(seen) {
+                        self.state.merge(&other);
                     }
                 }
-                CrdtMessageResponse::Responses(vec![Message::new_reply_to(
-                    message,
-                    Payload::GossipOk {
-                        seen: our_known.to_owned(),
-                    },
-                )])
+                let ids = deltas.iter().map(|(id, _)| id.to_owned()).collect();
+                // only deltas delivered for the first time are merged
+                for (id, _) in self.broadcast()?.receive(&message.src, deltas.to_owned())? {
+                    if let Some(delta) = parsed.remove(&id) {
+                        self.state.merge(&delta);
+                    }
+                }
+                let seen = self.broadcast()?.delivered().to_owned();
+                Ok(vec![message.reply(Payload::DeltasOk { ids, seen })])
             }
-            Payload::GossipOk { seen } => {
-                let their_known = self.known.entry(message.src.to_owned()).or_default();
-                their_known.extend(seen.iter().cloned());
-                CrdtMessageResponse::Responses(vec![])
+            Payload::DeltasOk { ids, seen } => {
+                self.broadcast()?.ack(&message.src, ids, seen)?;
+                Ok(vec![])
             }
-            Payload::AddOk { .. } | Payload::ReadOk { .. } => {
-                CrdtMessageResponse::Responses(vec![])
+            Payload::AddOk
+            | Payload::ReadOk { .. }
+            | Payload::Merge { .. }
+            | Payload::Delta { .. }
+            | Payload::DeltaOk { .. } => Ok(vec![]),
+        }
+    }
+
+    fn on_timer(&mut self, timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let Some(Batch {
+            dest,
+            items,
+            with_state,
+        }) = self.broadcast()?.on_timer(timer)?
+        else {
+            return Ok(vec![]);
+        };
+        let state = if with_state {
+            let state = serde_json::to_value(&self.state).map_err(|_| Error::Crash)?;
+            Some((state, self.broadcast()?.delivered().to_owned()))
+        } else {
+            None
+        };
+        Ok(vec![Message {
+            src: self.node_id(),
+            dest,
+            body: Body::new(Payload::Deltas {
+                deltas: items,
+                state,
+            }),
+        }])
+    }
+}
+
+/// Serve the `add`/`read` workloads with `C`, replicated as chosen with the `REPLICATION`
+/// environment variable: `delta` (the default) with a [`DeltaCrdtActor`], `broadcast` with a
+/// [`BroadcastCrdtActor`], or `state` with a [`CrdtActor`]. Any other value falls back to
+/// delta, with a warning.
+pub fn start<C: Crdt + Send + 'static>()
+where
+    // required by the derived `Default` of the actors
+    C::Op: Default,
+{
+    match std::env::var("REPLICATION").as_deref() {
+        Ok("delta") | Err(_) => Runtime::<DeltaCrdtActor<C>>::new().start(),
+        Ok("broadcast") => Runtime::<BroadcastCrdtActor<C>>::new().start(),
+        Ok("state") => Runtime::<CrdtActor<C>>::new().start(),
+        Ok(other) => {
+            eprintln!(
+                "unknown REPLICATION {}, expected delta, broadcast or state: using delta",
+                other
+            );
+            Runtime::<DeltaCrdtActor<C>>::new().start()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Handle,
+        sim::{NetworkConfig, Simulation},
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::mpsc;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    /// States reached by replicas applying random operations and merging each other's state at
+    /// random, along with the deltas of the operations
+    fn states<C: Crdt + Clone>(seed: u64, mut op: impl FnMut(&mut StdRng, &C) -> C::Op) -> Vec<C> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut replicas = vec![C::default(); NODES.len()];
+        let mut deltas = vec![];
+        for _ in 0..40 {
+            let i = rng.gen_range(0..NODES.len());
+            if rng.gen_bool(0.3) {
+                let other = replicas[rng.gen_range(0..NODES.len())].to_owned();
+                replicas[i].merge(&other);
+            } else {
+                let op = op(&mut rng, &replicas[i]);
+                // clocks of different nodes are not in sync
+                let now = at(rng.gen_range(0..20));
+                deltas.push(replicas[i].apply_local(NODES[i], now, op));
             }
         }
+        let picked: Vec<C> = (0..4)
+            .map(|_| deltas[rng.gen_range(0..deltas.len())].to_owned())
+            .collect();
+        replicas.into_iter().chain(picked).collect()
+    }
+
+    fn at(wall: u64) -> HlcTimestamp {
+        HlcTimestamp { wall, logical: 0 }
+    }
+
+    fn merged<C: Crdt + Clone>(a: &C, b: &C) -> C {
+        let mut merged = a.to_owned();
+        merged.merge(b);
+        merged
+    }
+
+    fn json<C: Crdt>(state: &C) -> Value {
+        serde_json::to_value(state).unwrap()
+    }
+
+    /// Merge is commutative, associative and idempotent over every state of `states`
+    fn check_laws<C: Crdt + Clone>(states: &[C]) {
+        for a in states {
+            assert_eq!(json(&merged(a, a)), json(a), "not idempotent");
+            for b in states {
+                assert_eq!(json(&merged(a, b)), json(&merged(b, a)), "not commutative");
+                for c in states {
+                    assert_eq!(
+                        json(&merged(&merged(a, b), c)),
+                        json(&merged(a, &merged(b, c))),
+                        "not associative"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn g_counter_merge_laws() {
+        for seed in 0..20 {
+            check_laws(&states::<GCounter>(seed, |rng, _| rng.gen_range(0..10)));
+        }
+    }
+
+    #[test]
+    fn pn_counter_merge_laws() {
+        for seed in 0..20 {
+            check_laws(&states::<PnCounter>(seed, |rng, _| rng.gen_range(-10..10)));
+        }
+    }
+
+    #[test]
+    fn g_set_merge_laws() {
+        for seed in 0..20 {
+            check_laws(&states::<GSet<u8>>(seed, |rng, _| rng.gen_range(0..8)));
+        }
+    }
+
+    #[test]
+    fn or_set_merge_laws() {
+        for seed in 0..20 {
+            let states = states::<OrSet<u8>>(seed, |rng, set| {
+                let element = rng.gen_range(0..4);
+                if set.contains(&element) && rng.gen_bool(0.5) {
+                    OrSetOp::Remove(element)
+                } else {
+                    OrSetOp::Add(element)
+                }
+            });
+            check_laws(&states);
+        }
+    }
+
+    #[test]
+    fn or_set_add_wins_over_a_concurrent_remove() {
+        let mut a = OrSet::default();
+        a.apply_local("n0", at(1), OrSetOp::Add(1));
+        let mut b = a.to_owned();
+        b.apply_local("n1", at(1), OrSetOp::Remove(1));
+        a.apply_local("n0", at(1), OrSetOp::Add(1));
+        a.merge(&b);
+        assert!(a.contains(&1));
+        // the remove only drops the additions it observed
+        b.merge(&a);
+        assert!(b.contains(&1));
+        b.apply_local("n1", at(1), OrSetOp::Remove(1));
+        a.merge(&b);
+        assert!(!a.contains(&1));
+    }
+
+    #[test]
+    fn lww_register_merge_laws() {
+        for seed in 0..20 {
+            check_laws(&states::<LwwRegister<u8>>(seed, |rng, _| rng.gen()));
+        }
+    }
+
+    #[test]
+    fn lww_register_keeps_the_latest_write() {
+        let mut a = LwwRegister::default();
+        a.apply_local("n0", at(1), 1);
+        let mut b = a.to_owned();
+        b.apply_local("n1", at(2), 2);
+        a.merge(&b);
+        assert_eq!(a.get(), Some(&2));
+        // a write made after seeing another is ordered after it, even when the clock is behind
+        a.apply_local("n0", at(1), 3);
+        let after = HlcTimestamp {
+            wall: 2,
+            logical: 1,
+        };
+        assert_eq!(a.timestamp().time, after);
+        b.merge(&a);
+        assert_eq!(b.get(), Some(&3));
+    }
+
+    fn delta_actor() -> DeltaCrdtActor<GSet<u64>> {
+        let mut actor = DeltaCrdtActor::default();
+        let (tx, _rx) = mpsc::channel();
+        let peers = NODES.iter().map(|n| n.to_string()).collect();
+        actor
+            .init(Handle::new("n0".to_owned(), tx), "n0".to_owned(), peers)
+            .unwrap();
+        actor
+    }
+
+    fn request(src: &str, payload: Payload<u64>) -> Message<Body<Payload<u64>>> {
+        Message {
+            src: src.to_owned(),
+            dest: "n0".to_owned(),
+            body: Body::new(payload),
+        }
+    }
+
+    /// The delta gossiped to each peer
+    fn gossip(actor: &mut DeltaCrdtActor<GSet<u64>>) -> HashMap<ActorID, Value> {
+        actor
+            .on_timer(TimerId(0))
+            .unwrap()
+            .into_iter()
+            .map(|msg| match msg.body.payload {
+                Payload::Delta { delta, .. } => (msg.dest, delta),
+                other => panic!("expected a delta, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn g_set(elements: impl IntoIterator<Item = u64>) -> Value {
+        json(&GSet {
+            elements: elements.into_iter().collect(),
+        })
+    }
+
+    #[test]
+    fn deltas_are_pruned_once_every_peer_acknowledged_them() {
+        let mut actor = delta_actor();
+        for delta in [1, 2, 3] {
+            actor
+                .receive(&request("c1", Payload::Add { delta }))
+                .unwrap();
+        }
+        let sent = gossip(&mut actor);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent["n1"], json(actor.state()));
+
+        actor
+            .receive(&request("n1", Payload::DeltaOk { seq: 3 }))
+            .unwrap();
+        assert_eq!(actor.deltas.len(), 3);
+        assert_eq!(gossip(&mut actor).keys().collect::<Vec<_>>(), ["n2"]);
+
+        actor
+            .receive(&request("n2", Payload::DeltaOk { seq: 2 }))
+            .unwrap();
+        assert_eq!(actor.deltas.len(), 1);
+        assert_eq!(gossip(&mut actor)["n2"], g_set([3]));
+
+        actor
+            .receive(&request("n2", Payload::DeltaOk { seq: 3 }))
+            .unwrap();
+        assert!(actor.deltas.is_empty());
+        assert!(gossip(&mut actor).is_empty());
+    }
+
+    #[test]
+    fn peers_behind_the_buffer_are_sent_the_full_state() {
+        let mut actor = delta_actor();
+        for seq in 1..=DELTA_BUFFER_SIZE as u64 + 2 {
+            actor
+                .receive(&request("c1", Payload::Add { delta: seq }))
+                .unwrap();
+            actor
+                .receive(&request("n1", Payload::DeltaOk { seq }))
+                .unwrap();
+            if seq == 1 {
+                actor
+                    .receive(&request("n2", Payload::DeltaOk { seq }))
+                    .unwrap();
+            }
+        }
+        assert_eq!(actor.deltas.len(), DELTA_BUFFER_SIZE);
+        actor
+            .receive(&request("c1", Payload::Add { delta: 0 }))
+            .unwrap();
+        let sent = gossip(&mut actor);
+        assert_eq!(sent["n1"], g_set([0]));
+        assert_eq!(sent["n2"], json(actor.state()));
+    }
+
+    #[test]
+    fn broadcast_deltas_reach_every_node_after_a_partition() {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+        };
+        let mut sim = Simulation::<BroadcastCrdtActor<GSet<u64>>>::new(5, 0, config).unwrap();
+        let nodes = sim.node_ids();
+        sim.partition(&[nodes[..2].to_vec(), nodes[2..].to_vec()]);
+        for element in 0..50u64 {
+            let node = &nodes[element as usize % nodes.len()];
+            sim.request("c1", node, &Body::new(Payload::Add { delta: element }));
+            sim.run_for(Duration::from_millis(20));
+        }
+        sim.run_for(Duration::from_secs(1));
+        assert!(
+            sim.actor("n0")
+                .unwrap()
+                .state()
+                .value()
+                .as_array()
+                .unwrap()
+                .len()
+                < 50
+        );
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(10));
+        let expected = g_set(0..50);
+        for node in &nodes {
+            let actor = sim.actor(node).unwrap();
+            assert_eq!(json(actor.state()), expected, "{}", node);
+            assert_eq!(actor.broadcast.as_ref().unwrap().unacknowledged(), 0);
+        }
+    }
+
+    #[test]
+    fn broadcast_deltas_are_dropped_once_acknowledged() {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+        };
+        let mut sim = Simulation::<BroadcastCrdtActor<GCounter>>::new(5, 0, config).unwrap();
+        let nodes = sim.node_ids();
+        for op in 0..500 {
+            let node = &nodes[op % nodes.len()];
+            sim.request("c1", node, &Body::new(Payload::Add { delta: 1 }));
+            sim.run_for(Duration::from_millis(2));
+        }
+        sim.run_for(Duration::from_secs(5));
+        for node in &nodes {
+            let actor = sim.actor(node).unwrap();
+            assert_eq!(actor.state().value(), serde_json::json!(500), "{}", node);
+            // all that is left of 500 deltas is a count per node
+            let broadcast = actor.broadcast.as_ref().unwrap();
+            assert_eq!(broadcast.unacknowledged(), 0, "{}", node);
+            assert_eq!(broadcast.out_of_order(), 0, "{}", node);
+            assert_eq!(broadcast.delivered().iter().count(), nodes.len());
+            assert_eq!(
+                broadcast.delivered().iter().map(|(_, n)| n).sum::<u64>(),
+                500
+            );
+        }
     }
 }
@@ -1,4 +1,9 @@
 //! Error type, defined by Maelstrom
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::MessageID;
 
 /// Errors that can be thrown by Maelstrom and/or the user
 /// Taken from the [Error doc](https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors).
@@ -34,6 +39,25 @@ pub enum Error {
 }
 
 impl Error {
+    /// Build an Error from a code received over the wire.
+    /// Unknown codes are turned into a `CustomError` carrying `text`.
+    pub fn from_code(code: u64, text: String) -> Error {
+        match code {
+            0 => Error::Timeout,
+            1 => Error::NodeNotFound,
+            10 => Error::NotSupported,
+            11 => Error::TemporarilyUnavailable,
+            12 => Error::MalformedRequest,
+            13 => Error::Crash,
+            14 => Error::Abort,
+            20 => Error::KeyDoesNotExist,
+            21 => Error::KeyAlreadyExist,
+            22 => Error::PreconditionFailed,
+            30 => Error::TxnConflict,
+            _ => Error::CustomError((code, text)),
+        }
+    }
+
     /// retrieve the code of the Error
     /// Might return None if the custom error is not above 10_000
     pub fn get_code(&self) -> Option<u64> {
@@ -59,3 +83,46 @@ impl Error {
         }
     }
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "timeout"),
+            Error::NodeNotFound => write!(f, "node not found"),
+            Error::NotSupported => write!(f, "not supported"),
+            Error::TemporarilyUnavailable => write!(f, "temporarily unavailable"),
+            Error::MalformedRequest => write!(f, "malformed request"),
+            Error::Crash => write!(f, "crash"),
+            Error::Abort => write!(f, "abort"),
+            Error::KeyDoesNotExist => write!(f, "key does not exist"),
+            Error::KeyAlreadyExist => write!(f, "key already exists"),
+            Error::PreconditionFailed => write!(f, "precondition failed"),
+            Error::TxnConflict => write!(f, "txn conflict"),
+            Error::CustomError((_, text)) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// Body of an `error` message, as sent back to the node which made the failed request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorBody {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub code: u64,
+    pub text: String,
+    /// Taken by the [`Body`](crate::message::Body) when an error is the payload of one
+    #[serde(default)]
+    pub in_reply_to: MessageID,
+}
+
+impl ErrorBody {
+    /// Errors without a valid code are reported as a `Crash`, since we cannot tell whether they took place
+    pub fn new(error: &Error, in_reply_to: MessageID) -> Self {
+        Self {
+            message_type: "error".to_owned(),
+            code: error.get_code().unwrap_or(13),
+            text: error.to_string(),
+            in_reply_to,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Unique IDs generated without coordination between nodes.
+//!
+//! A [`Snowflake`] id is a 64-bit integer made of the milliseconds elapsed since
+//! [`SNOWFLAKE_EPOCH_MS`] (41 bits), the index of the node (10 bits) and a sequence number
+//! (12 bits) distinguishing the ids generated within the same millisecond. Ids of a node are
+//! strictly increasing, and ids of different nodes roughly follow wall-clock time.
+use std::time::SystemTime;
+
+use crate::{clock::unix_millis, errors::Error};
+
+/// 2020-01-01T00:00:00Z, in milliseconds since the UNIX epoch
+pub const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+/// Highest node index a [`Snowflake`] generator can be given
+pub const MAX_NODE_INDEX: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// Generator of Snowflake ids for one node
+#[derive(Clone, Debug)]
+pub struct Snowflake {
+    node_index: u64,
+    /// Millisecond of the latest id, which may be ahead of the clock
+    last_ms: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    /// Fails if `node_index` is above [`MAX_NODE_INDEX`]
+    pub fn new(node_index: u64) -> Result<Self, Error> {
+        if node_index > MAX_NODE_INDEX {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(Self {
+            node_index,
+            last_ms: 0,
+            sequence: 0,
+        })
+    }
+
+    /// Generator for a node named like Maelstrom names them, `n` followed by its index
+    pub fn for_node(node_id: &str) -> Result<Self, Error> {
+        node_id
+            .strip_prefix('n')
+            .and_then(|index| index.parse().ok())
+            .ok_or(Error::MalformedRequest)
+            .and_then(Self::new)
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id_at(unix_millis(SystemTime::now()))
+    }
+
+    /// Id generated at `now_ms`, in milliseconds since the UNIX epoch. When the clock goes
+    /// backwards, or when the sequence of a millisecond runs out, ids carry on from the latest
+    /// millisecond used rather than waiting for the clock to catch up.
+    pub fn next_id_at(&mut self, now_ms: u64) -> u64 {
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.sequence = 0;
+        } else if self.sequence < MAX_SEQUENCE {
+            self.sequence += 1;
+        } else {
+            self.last_ms += 1;
+            self.sequence = 0;
+        }
+        let elapsed = self.last_ms.saturating_sub(SNOWFLAKE_EPOCH_MS);
+        elapsed << (NODE_BITS + SEQUENCE_BITS) | self.node_index << SEQUENCE_BITS | self.sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01T00:00:00Z
+    const NOW_MS: u64 = 1_704_067_200_000;
+
+    fn parts(id: u64) -> (u64, u64, u64) {
+        (
+            id >> (NODE_BITS + SEQUENCE_BITS),
+            id >> SEQUENCE_BITS & MAX_NODE_INDEX,
+            id & MAX_SEQUENCE,
+        )
+    }
+
+    #[test]
+    fn ids_increase_monotonically() {
+        let mut generator = Snowflake::new(3).unwrap();
+        let ids: Vec<u64> = (0..10)
+            .flat_map(|ms| [NOW_MS + ms, NOW_MS + ms, NOW_MS + ms])
+            .map(|now_ms| generator.next_id_at(now_ms))
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(parts(ids[0]), (NOW_MS - SNOWFLAKE_EPOCH_MS, 3, 0));
+        assert_eq!(parts(ids[2]), (NOW_MS - SNOWFLAKE_EPOCH_MS, 3, 2));
+        assert_eq!(parts(ids[3]), (NOW_MS + 1 - SNOWFLAKE_EPOCH_MS, 3, 0));
+    }
+
+    #[test]
+    fn ids_carry_on_when_the_clock_goes_backwards() {
+        let mut generator = Snowflake::new(1).unwrap();
+        let before = generator.next_id_at(NOW_MS);
+        let after = generator.next_id_at(NOW_MS - 1_000);
+        assert!(after > before);
+        assert_eq!(parts(after), (NOW_MS - SNOWFLAKE_EPOCH_MS, 1, 1));
+    }
+
+    #[test]
+    fn a_full_sequence_moves_to_the_next_millisecond() {
+        let mut generator = Snowflake::new(1).unwrap();
+        let ids: Vec<u64> = (0..=MAX_SEQUENCE + 1)
+            .map(|_| generator.next_id_at(NOW_MS))
+            .collect();
+        let elapsed = NOW_MS - SNOWFLAKE_EPOCH_MS;
+        assert_eq!(
+            parts(ids[MAX_SEQUENCE as usize]),
+            (elapsed, 1, MAX_SEQUENCE)
+        );
+        assert_eq!(parts(*ids.last().unwrap()), (elapsed + 1, 1, 0));
+        // the clock catching up does not reuse the millisecond borrowed
+        let next = generator.next_id_at(NOW_MS + 1);
+        assert_eq!(parts(next), (elapsed + 1, 1, 1));
+    }
+
+    #[test]
+    fn node_indexes_fit_in_ten_bits() {
+        let mut generator = Snowflake::new(MAX_NODE_INDEX).unwrap();
+        let id = generator.next_id_at(NOW_MS);
+        assert_eq!(parts(id), (NOW_MS - SNOWFLAKE_EPOCH_MS, 1023, 0));
+        assert!(matches!(
+            Snowflake::new(MAX_NODE_INDEX + 1),
+            Err(Error::MalformedRequest)
+        ));
+    }
+
+    #[test]
+    fn node_ids_are_parsed_like_maelstrom_names_them() {
+        assert_eq!(Snowflake::for_node("n12").unwrap().node_index, 12);
+        for node_id in ["c1", "n", "nx", "n-1", "n1024", "12"] {
+            assert!(Snowflake::for_node(node_id).is_err(), "{}", node_id);
+        }
+    }
+}
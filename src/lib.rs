@@ -2,4 +2,19 @@ pub mod errors;
 pub mod actor;
 pub mod message;
 pub mod runtime;
+pub mod rpc;
+pub mod timer;
+pub mod sim;
+pub mod services;
 pub mod crdt;
+pub mod clock;
+pub mod ids;
+pub mod append_log;
+pub mod txn;
+pub mod raft;
+pub mod paxos;
+pub mod state_machine;
+pub mod broadcast;
+pub mod topology;
+#[cfg(feature = "async")]
+pub mod async_runtime;
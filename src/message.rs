@@ -1,11 +1,66 @@
-use crate::actor::ActorID;
-use serde::{Deserialize, Serialize};
+use crate::{actor::ActorID, errors::Error};
+use serde::{
+    de::{self, value::MapDeserializer},
+    Deserialize, Serialize,
+};
+use serde_json::Value;
+use std::{
+    fmt, iter,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 /// Message IDs should be unique on the node which sent them. For instance, each node can use a monotonically increasing integer as their source of message IDs.
 pub type MessageID = u64;
 
+/// Hands out monotonically increasing msg_ids, starting at 1.
+/// Clones share the same counter, so a node should use a single allocator.
+#[derive(Clone, Default, Debug)]
+pub struct MessageIdAllocator(Arc<AtomicU64>);
+
+impl MessageIdAllocator {
+    pub fn next(&self) -> MessageID {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Body of a message: the standard headers, next to the user payload.
+/// The payload is flattened, so it is expected to carry the `type` tag itself
+/// (e.g. an enum with `#[serde(tag = "type")]`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Body<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<MessageID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<MessageID>,
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+impl<T> Body<T> {
+    /// A body without any header
+    pub fn new(payload: T) -> Self {
+        Self {
+            msg_id: None,
+            in_reply_to: None,
+            payload,
+        }
+    }
+
+    /// A body expecting a reply, identified by `msg_id`
+    pub fn with_msg_id(msg_id: MessageID, payload: T) -> Self {
+        Self {
+            msg_id: Some(msg_id),
+            in_reply_to: None,
+            payload,
+        }
+    }
+}
+
 /// A request from the Maelstrom system
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<T> {
     /// Source of the request
     pub src: ActorID,
@@ -25,14 +80,188 @@ impl<T> Message<T> {
     }
 }
 
+impl<T> Message<Body<T>> {
+    /// Reply to this message with `payload`, filling `in_reply_to` with our msg_id
+    pub fn reply<U>(&self, payload: U) -> Message<Body<U>> {
+        Message {
+            src: self.dest.to_owned(),
+            dest: self.src.to_owned(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: self.body.msg_id,
+                payload,
+            },
+        }
+    }
+}
+
 impl<T: Serialize> Message<T> {
     pub fn serialize(self) -> String {
         serde_json::to_string(&self).expect("expected response to marshall to json")
     }
+
+    /// Turn the body into plain JSON, e.g. to route the message without knowing its payload type
+    pub fn into_value(self) -> Message<Value> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: serde_json::to_value(self.body).expect("expected response to marshall to json"),
+        }
+    }
 }
 
 impl<T: for<'de> Deserialize<'de>> Message<T> {
-    pub fn deserialize(buffer: &String) -> Self {
-        serde_json::from_slice(buffer.as_bytes()).expect("expected valid payload")
+    /// Parse a line of JSON. See [`Message::decode`] for how failures are classified.
+    pub fn deserialize(buffer: &str) -> Result<Self, Error> {
+        serde_json::from_str(buffer).map_err(|_| {
+            match serde_json::from_str::<Message<Value>>(buffer) {
+                Ok(msg) => classify_error::<T>(msg.message_type()),
+                Err(_) => Error::MalformedRequest,
+            }
+        })
+    }
+}
+
+/// Bodies with a `type` which `T` does not model are `NotSupported`,
+/// anything else that does not fit is a `MalformedRequest`
+fn classify_error<T: for<'de> Deserialize<'de>>(message_type: Option<&str>) -> Error {
+    match message_type {
+        Some(message_type) if !supports_type::<T>(message_type) => Error::NotSupported,
+        _ => Error::MalformedRequest,
+    }
+}
+
+/// Whether `T` has a variant for bodies of type `message_type`.
+///
+/// `T` is handed a body holding nothing but the `type` field: it fails with `unknown_variant`
+/// only when the tag is not one of its variants. Types which are not enums accept any tag.
+pub fn supports_type<T: for<'de> Deserialize<'de>>(message_type: &str) -> bool {
+    let probe = MapDeserializer::<_, ProbeError>::new(iter::once(("type", message_type)));
+    !matches!(T::deserialize(probe), Err(ProbeError::UnknownVariant))
+}
+
+/// Error of [`supports_type`], telling an unknown tag apart from everything else
+#[derive(Debug)]
+enum ProbeError {
+    UnknownVariant,
+    Other,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::UnknownVariant => write!(f, "unknown variant"),
+            ProbeError::Other => write!(f, "not a probe"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl de::Error for ProbeError {
+    fn custom<M: fmt::Display>(_msg: M) -> Self {
+        ProbeError::Other
+    }
+
+    fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+        ProbeError::UnknownVariant
+    }
+}
+
+impl Message<Value> {
+    /// Parse the body into a typed payload.
+    /// Unknown message types yield `Error::NotSupported`, other mismatches `Error::MalformedRequest`.
+    pub fn decode<T: for<'de> Deserialize<'de>>(self) -> Result<Message<T>, Error> {
+        let message_type = self.message_type().map(str::to_owned);
+        let body = serde_json::from_value(self.body)
+            .map_err(|_| classify_error::<T>(message_type.as_deref()))?;
+        Ok(Message {
+            src: self.src,
+            dest: self.dest,
+            body,
+        })
+    }
+
+    /// `type` field of the body, if any
+    pub fn message_type(&self) -> Option<&str> {
+        self.body.get("type").and_then(Value::as_str)
+    }
+
+    /// `msg_id` field of the body, if any
+    pub fn msg_id(&self) -> Option<MessageID> {
+        self.body.get("msg_id").and_then(Value::as_u64)
+    }
+
+    /// `in_reply_to` field of the body, if any
+    pub fn in_reply_to(&self) -> Option<MessageID> {
+        self.body.get("in_reply_to").and_then(Value::as_u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Echo { echo: Value },
+        Read,
+    }
+
+    fn decode(body: Value) -> Result<Message<Body<Payload>>, Error> {
+        Message {
+            src: "c1".to_owned(),
+            dest: "n1".to_owned(),
+            body,
+        }
+        .decode()
+    }
+
+    #[test]
+    fn valid_bodies_are_decoded() {
+        let msg = decode(json!({"type": "echo", "msg_id": 1, "echo": "hi"})).unwrap();
+        assert_eq!(msg.body.msg_id, Some(1));
+        assert!(matches!(msg.body.payload, Payload::Echo { echo } if echo == "hi"));
+    }
+
+    #[test]
+    fn unknown_types_are_not_supported() {
+        let e = decode(json!({"type": "generate", "msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::NotSupported));
+    }
+
+    #[test]
+    fn known_types_with_bad_fields_are_malformed() {
+        let e = decode(json!({"type": "echo", "msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+        let e = decode(json!({"type": "read", "msg_id": "one"})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+    }
+
+    #[test]
+    fn bodies_without_a_type_are_malformed() {
+        let e = decode(json!({"msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+    }
+
+    #[test]
+    fn lines_are_classified_like_bodies() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"topology"}}"#;
+        let e = Message::<Body<Payload>>::deserialize(line).unwrap_err();
+        assert!(matches!(e, Error::NotSupported));
+        let e = Message::<Body<Payload>>::deserialize("{\"src\":").unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+    }
+
+    #[test]
+    fn supported_types() {
+        assert!(supports_type::<Payload>("echo"));
+        assert!(supports_type::<Body<Payload>>("read"));
+        assert!(!supports_type::<Body<Payload>>("read_ok"));
+        // only enums look at the tag
+        assert!(supports_type::<Value>("anything"));
     }
 }
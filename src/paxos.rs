@@ -0,0 +1,1079 @@
+//! Paxos consensus, replicating the commands of a [`StateMachine`] across the nodes.
+//!
+//! Taken from [Paxos Made Simple](https://lamport.azurewebsites.net/pubs/paxos-simple.pdf).
+//! [`Acceptor`] and [`Proposer`] implement single-decree Paxos, which chooses a single value.
+//! [`MultiPaxos`] chooses the request of every slot of a log: a stable leader runs phase 1 once
+//! for all the slots it has yet to fill, then only phase 2 for each request, with an acceptor and
+//! a proposer per slot. Chosen requests are applied in slot order on every node, and requests
+//! sent to a follower are forwarded to the leader, like with [`Raft`](crate::raft::Raft).
+//!
+//! Applied slots are forgotten every [`SNAPSHOT_INTERVAL`] slots. Nodes which have not applied
+//! them are sent a snapshot of the state machine instead.
+use crate::{
+    actor::{Actor, ActorID},
+    errors::{Error, ErrorBody},
+    message::{supports_type, Body, Message, MessageID},
+    rpc::{self, RpcReply},
+    runtime::Handle,
+    state_machine::StateMachine,
+    timer::TimerId,
+};
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{btree_map, BTreeMap, HashMap, HashSet},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+/// How often the leader sends heartbeats and retransmits its pending proposals
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// A follower which has not heard from a leader for a random time in this range runs for leader
+pub const ELECTION_TIMEOUT: RangeInclusive<Duration> =
+    Duration::from_millis(300)..=Duration::from_millis(600);
+/// Cap on the chosen entries sent to a lagging node in a single message
+pub const MAX_DECIDED_PER_MESSAGE: usize = 64;
+/// Number of applied slots remembered before they are forgotten
+pub const SNAPSHOT_INTERVAL: Slot = 256;
+
+/// Position of a request in the replicated log, starting at 0
+pub type Slot = u64;
+
+/// Proposal number. Every node proposes with its own ballots, so two proposers never share one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: ActorID,
+}
+
+impl Ballot {
+    /// Lowest ballot of `node` above `self`
+    pub fn next(&self, node: &ActorID) -> Ballot {
+        Ballot {
+            round: self.round + 1,
+            node: node.to_owned(),
+        }
+    }
+}
+
+/// Acceptor of single-decree Paxos
+#[derive(Debug, Clone)]
+pub struct Acceptor<V> {
+    promised: Option<Ballot>,
+    accepted: Option<(Ballot, V)>,
+}
+
+impl<V> Default for Acceptor<V> {
+    fn default() -> Self {
+        Self {
+            promised: None,
+            accepted: None,
+        }
+    }
+}
+
+impl<V: Clone> Acceptor<V> {
+    /// Phase 1b: promise to ignore the ballots below `ballot`, returning the value accepted with
+    /// the highest ballot so far. Fails with the ballot promised instead.
+    pub fn prepare(&mut self, ballot: &Ballot) -> Result<Option<(Ballot, V)>, Ballot> {
+        match &self.promised {
+            Some(promised) if promised > ballot => Err(promised.to_owned()),
+            _ => {
+                self.promised = Some(ballot.to_owned());
+                Ok(self.accepted.to_owned())
+            }
+        }
+    }
+
+    /// Phase 2b: accept `value`, unless a higher ballot was promised, which is returned instead
+    pub fn accept(&mut self, ballot: &Ballot, value: V) -> Result<(), Ballot> {
+        match &self.promised {
+            Some(promised) if promised > ballot => Err(promised.to_owned()),
+            _ => {
+                self.promised = Some(ballot.to_owned());
+                self.accepted = Some((ballot.to_owned(), value));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn promised(&self) -> Option<&Ballot> {
+        self.promised.as_ref()
+    }
+
+    pub fn accepted(&self) -> Option<&(Ballot, V)> {
+        self.accepted.as_ref()
+    }
+}
+
+/// Proposer of single-decree Paxos, counting the answers of the acceptors to one ballot
+#[derive(Debug, Clone)]
+pub struct Proposer<V> {
+    ballot: Ballot,
+    value: V,
+    quorum: usize,
+    /// Ballot of the value we adopted from a promise, if any
+    adopted: Option<Ballot>,
+    promises: HashSet<ActorID>,
+    accepts: HashSet<ActorID>,
+}
+
+impl<V> Proposer<V> {
+    /// Propose `value` with `ballot`, to acceptors of which `quorum` must agree
+    pub fn new(ballot: Ballot, value: V, quorum: usize) -> Self {
+        Self {
+            ballot,
+            value,
+            quorum,
+            adopted: None,
+            promises: HashSet::new(),
+            accepts: HashSet::new(),
+        }
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.ballot
+    }
+
+    /// Value to send in phase 2a
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+
+    pub fn into_value(self) -> V {
+        self.value
+    }
+
+    /// Phase 1b: record the promise of `from`, which may have accepted a value already.
+    /// Returns true once, when a quorum has promised: from then on, [`Proposer::value`] is the
+    /// value accepted with the highest ballot, or ours if no acceptor of the quorum accepted any.
+    pub fn on_promise(&mut self, from: &ActorID, accepted: Option<(Ballot, V)>) -> bool {
+        if let Some((ballot, value)) = accepted {
+            if self
+                .adopted
+                .as_ref()
+                .is_none_or(|adopted| ballot > *adopted)
+            {
+                self.adopted = Some(ballot);
+                self.value = value;
+            }
+        }
+        self.promises.insert(from.to_owned()) && self.promises.len() == self.quorum
+    }
+
+    /// Phase 2b: record that `from` accepted our value.
+    /// Returns true once, when a quorum has accepted it: the value is chosen.
+    pub fn on_accepted(&mut self, from: &ActorID) -> bool {
+        self.accepts.insert(from.to_owned()) && self.accepts.len() == self.quorum
+    }
+}
+
+/// Messages exchanged between the nodes. `None` values are no-ops, which a new leader proposes
+/// for the slots left empty by the previous one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum PaxosMessage<R> {
+    /// Phase 1a, for every slot from `from_slot` on
+    Prepare { ballot: Ballot, from_slot: Slot },
+    /// Phase 1b, with what the sender accepted from `from_slot` on. If it forgot slots from
+    /// `from_slot` on, with a snapshot of its state machine and the number of slots it applied.
+    Promise {
+        ballot: Ballot,
+        accepted: Vec<(Slot, Ballot, Option<R>)>,
+        snapshot: Option<(Slot, serde_json::Value)>,
+    },
+    /// Phase 2a
+    Accept {
+        ballot: Ballot,
+        slot: Slot,
+        value: Option<R>,
+    },
+    /// Phase 2b
+    Accepted { ballot: Ballot, slot: Slot },
+    /// The sender promised `promised`, so it ignored a request with a lower ballot
+    Nack { promised: Ballot },
+    /// Sent by the leader to keep its followers from running for leader
+    Heartbeat { ballot: Ballot },
+    /// Number of slots the sender applied
+    HeartbeatOk { applied: Slot },
+    /// Chosen values, with the ballot they were chosen with
+    Decided {
+        entries: Vec<(Slot, Ballot, Option<R>)>,
+    },
+    /// Snapshot of the state machine with the slots below `slot` applied, sent instead of
+    /// chosen values the sender forgot
+    Snapshot {
+        slot: Slot,
+        state: serde_json::Value,
+    },
+}
+
+/// Payload of every message a [`MultiPaxos`] node sends or receives: Paxos messages between
+/// the nodes, and the commands and outputs of the state machine, as the requests and replies of
+/// clients. `Error` relays the errors the leader answered with to the clients of a follower.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+#[serde(bound = "")]
+pub enum PaxosPayload<S: StateMachine> {
+    Paxos(PaxosMessage<S::Command>),
+    Request(S::Command),
+    Response(S::Output),
+    Error(ErrorBody),
+}
+
+/// Told apart by their `type` tag, like [`RaftPayload`](crate::raft::RaftPayload)
+impl<'de, S: StateMachine> Deserialize<'de> for PaxosPayload<S> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let body = serde_json::Value::deserialize(deserializer)?;
+        let message_type = body
+            .get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| de::Error::missing_field("type"))?
+            .to_owned();
+        let payload = if message_type == "error" {
+            serde_json::from_value(body).map(PaxosPayload::Error)
+        } else if supports_type::<PaxosMessage<S::Command>>(&message_type) {
+            serde_json::from_value(body).map(PaxosPayload::Paxos)
+        } else if supports_type::<S::Command>(&message_type) {
+            serde_json::from_value(body).map(PaxosPayload::Request)
+        } else if supports_type::<S::Output>(&message_type) {
+            serde_json::from_value(body).map(PaxosPayload::Response)
+        } else {
+            return Err(de::Error::unknown_variant(&message_type, &[]));
+        };
+        payload.map_err(de::Error::custom)
+    }
+}
+
+/// Derived, it would require the state machine itself to be `Clone`
+impl<S: StateMachine> Clone for PaxosPayload<S> {
+    fn clone(&self) -> Self {
+        match self {
+            PaxosPayload::Paxos(message) => PaxosPayload::Paxos(message.to_owned()),
+            PaxosPayload::Request(request) => PaxosPayload::Request(request.to_owned()),
+            PaxosPayload::Response(response) => PaxosPayload::Response(response.to_owned()),
+            PaxosPayload::Error(error) => PaxosPayload::Error(error.to_owned()),
+        }
+    }
+}
+
+type Value<S> = Option<<S as StateMachine>::Command>;
+/// Message of any kind sent or received by a [`MultiPaxos`] node
+type Envelope<S> = Message<Body<PaxosPayload<S>>>;
+
+#[derive(Debug)]
+enum Role<V> {
+    Follower,
+    /// Running phase 1 with `ballot`: promises so far, and the value accepted with the highest
+    /// ballot in each slot they reported
+    Candidate {
+        ballot: Ballot,
+        promises: HashSet<ActorID>,
+        accepted: BTreeMap<Slot, (Ballot, V)>,
+    },
+    /// Phase 1 succeeded for every slot from `next_slot` on
+    Leader {
+        ballot: Ballot,
+        next_slot: Slot,
+        proposals: BTreeMap<Slot, Proposer<V>>,
+    },
+}
+
+/// A node of a Multi-Paxos cluster replicating `S`. Every node is an acceptor and a learner,
+/// and the leader is the only proposer.
+pub struct MultiPaxos<S: StateMachine> {
+    node_id: Option<ActorID>,
+    peers: Vec<ActorID>,
+    handle: Option<Handle<Body<PaxosPayload<S>>>>,
+    machine: S,
+    role: Role<Value<S>>,
+    /// Highest ballot promised, for every slot
+    promised: Option<Ballot>,
+    acceptors: BTreeMap<Slot, Acceptor<Value<S>>>,
+    chosen: BTreeMap<Slot, (Ballot, Value<S>)>,
+    /// Slots below this one are applied
+    applied: Slot,
+    /// Slots below this one are forgotten
+    compacted: Slot,
+    election_timer: Option<TimerId>,
+    /// Requests we proposed as the leader, with our ballot, answered once applied
+    clients: HashMap<Slot, (Ballot, Envelope<S>)>,
+    /// Requests forwarded to the leader, by msg_id of the forwarded request
+    forwarded: HashMap<MessageID, Envelope<S>>,
+}
+
+impl<S: StateMachine> Default for MultiPaxos<S> {
+    fn default() -> Self {
+        Self {
+            node_id: None,
+            peers: vec![],
+            handle: None,
+            machine: S::default(),
+            role: Role::Follower,
+            promised: None,
+            acceptors: BTreeMap::new(),
+            chosen: BTreeMap::new(),
+            applied: 0,
+            compacted: 0,
+            election_timer: None,
+            clients: HashMap::new(),
+            forwarded: HashMap::new(),
+        }
+    }
+}
+
+impl<S: StateMachine> MultiPaxos<S> {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Node currently believed to be the leader, if any: the owner of the highest ballot promised
+    pub fn leader(&self) -> Option<&ActorID> {
+        self.promised.as_ref().map(|ballot| &ballot.node)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// Number of slots applied
+    pub fn applied(&self) -> Slot {
+        self.applied
+    }
+
+    /// The state machine, with every chosen command applied
+    pub fn state_machine(&self) -> &S {
+        &self.machine
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster_size = self.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn message(&self, dest: &ActorID, message: PaxosMessage<S::Command>) -> Envelope<S> {
+        Message {
+            src: self.node_id(),
+            dest: dest.to_owned(),
+            body: Body::new(PaxosPayload::Paxos(message)),
+        }
+    }
+
+    fn broadcast(&self, message: PaxosMessage<S::Command>) -> Vec<Envelope<S>> {
+        self.peers
+            .iter()
+            .map(|peer| self.message(peer, message.to_owned()))
+            .collect()
+    }
+
+    /// Start waiting for a leader again, for a new random timeout
+    fn reset_election_timer(&mut self) -> Result<(), Error> {
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        if let Some(timer) = self.election_timer.take() {
+            handle.cancel(timer)?;
+        }
+        let timeout = handle.rng().gen_range(ELECTION_TIMEOUT);
+        self.election_timer = Some(handle.schedule_once(timeout)?);
+        Ok(())
+    }
+
+    /// Ballot we lead or campaign with, if any
+    fn ballot(&self) -> Option<&Ballot> {
+        match &self.role {
+            Role::Follower => None,
+            Role::Candidate { ballot, .. } | Role::Leader { ballot, .. } => Some(ballot),
+        }
+    }
+
+    /// Promise `ballot` for every slot, stepping down if it beats ours.
+    /// Returns the ballot promised instead if it is lower than that.
+    fn promise(&mut self, ballot: &Ballot) -> Result<Result<(), Ballot>, Error> {
+        if let Some(promised) = self.promised.as_ref().filter(|p| *p > ballot) {
+            return Ok(Err(promised.to_owned()));
+        }
+        self.promised = Some(ballot.to_owned());
+        if self.ballot().is_some_and(|ours| ours < ballot) {
+            self.become_follower()?;
+        }
+        Ok(Ok(()))
+    }
+
+    fn become_follower(&mut self) -> Result<(), Error> {
+        eprintln!("{} is now a follower", self.node_id());
+        self.role = Role::Follower;
+        // our proposals may lose to the new leader's: their clients time out, as they may still be chosen
+        self.clients.clear();
+        self.reset_election_timer()
+    }
+
+    /// What we accepted from `from_slot` on
+    fn accepted_from(&self, from_slot: Slot) -> Vec<(Slot, Ballot, Value<S>)> {
+        self.acceptors
+            .range(from_slot..)
+            .filter_map(|(slot, acceptor)| {
+                let (ballot, value) = acceptor.accepted()?;
+                Some((*slot, ballot.to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    /// Run phase 1 with a ballot above every one we have seen, for the slots we have not applied
+    fn campaign(&mut self) -> Result<Vec<Envelope<S>>, Error> {
+        let ballot = match &self.promised {
+            Some(promised) => promised.next(&self.node_id()),
+            None => Ballot {
+                round: 1,
+                node: self.node_id(),
+            },
+        };
+        eprintln!(
+            "{} runs for leader with round {}",
+            self.node_id(),
+            ballot.round
+        );
+        self.promised = Some(ballot.to_owned());
+        self.role = Role::Candidate {
+            ballot: ballot.to_owned(),
+            promises: HashSet::new(),
+            accepted: BTreeMap::new(),
+        };
+        self.reset_election_timer()?;
+        let accepted = self.accepted_from(self.applied);
+        let mut out = self.on_promise(&self.node_id(), &ballot, accepted, None)?;
+        out.extend(self.broadcast(PaxosMessage::Prepare {
+            ballot,
+            from_slot: self.applied,
+        }));
+        Ok(out)
+    }
+
+    fn on_promise(
+        &mut self,
+        from: &ActorID,
+        ballot: &Ballot,
+        reported: Vec<(Slot, Ballot, Value<S>)>,
+        snapshot: Option<(Slot, serde_json::Value)>,
+    ) -> Result<Vec<Envelope<S>>, Error> {
+        if self.ballot() != Some(ballot) || !matches!(self.role, Role::Candidate { .. }) {
+            return Ok(vec![]);
+        }
+        // slots the sender forgot are applied, so we catch up before proposing after them
+        if let Some((slot, state)) = snapshot {
+            self.restore(slot, state)?;
+        }
+        let quorum = self.quorum();
+        let Role::Candidate {
+            promises, accepted, ..
+        } = &mut self.role
+        else {
+            return Ok(vec![]);
+        };
+        for (slot, ballot, value) in reported {
+            match accepted.get(&slot) {
+                Some((highest, _)) if *highest >= ballot => {}
+                _ => {
+                    accepted.insert(slot, (ballot, value));
+                }
+            }
+        }
+        if !(promises.insert(from.to_owned()) && promises.len() == quorum) {
+            return Ok(vec![]);
+        }
+        let accepted = std::mem::take(accepted);
+        Ok(self.become_leader(ballot.to_owned(), accepted))
+    }
+
+    /// Phase 1 succeeded: propose again what was accepted in the slots we have not seen chosen,
+    /// and fill the gaps between them with no-ops
+    fn become_leader(
+        &mut self,
+        ballot: Ballot,
+        accepted: BTreeMap<Slot, (Ballot, Value<S>)>,
+    ) -> Vec<Envelope<S>> {
+        eprintln!(
+            "{} is the leader with round {}",
+            self.node_id(),
+            ballot.round
+        );
+        if let (Some(handle), Some(timer)) = (self.handle.as_ref(), self.election_timer.take()) {
+            let _ = handle.cancel(timer);
+        }
+        let last = [accepted.keys().next_back(), self.chosen.keys().next_back()]
+            .into_iter()
+            .flatten()
+            .max()
+            .map_or(self.applied, |slot| self.applied.max(slot + 1));
+        self.role = Role::Leader {
+            ballot,
+            next_slot: last,
+            proposals: BTreeMap::new(),
+        };
+        let mut accepted = accepted;
+        let mut out = vec![];
+        for slot in self.applied..last {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let value = accepted.remove(&slot).and_then(|(_, value)| value);
+            out.extend(self.propose(slot, value));
+        }
+        out
+    }
+
+    /// Run phase 2 for `value` in `slot`, as the leader
+    fn propose(&mut self, slot: Slot, value: Value<S>) -> Vec<Envelope<S>> {
+        let quorum = self.quorum();
+        let Role::Leader {
+            ballot, proposals, ..
+        } = &mut self.role
+        else {
+            return vec![];
+        };
+        let ballot = ballot.to_owned();
+        proposals.insert(
+            slot,
+            Proposer::new(ballot.to_owned(), value.to_owned(), quorum),
+        );
+        let mut out = self.broadcast(PaxosMessage::Accept {
+            ballot: ballot.to_owned(),
+            slot,
+            value: value.to_owned(),
+        });
+        // we accept our own proposals, which no higher ballot can have beaten while we lead
+        let _ = self
+            .acceptors
+            .entry(slot)
+            .or_default()
+            .accept(&ballot, value);
+        out.extend(self.on_accepted(&self.node_id(), &ballot, slot));
+        out
+    }
+
+    fn on_accepted(&mut self, from: &ActorID, ballot: &Ballot, slot: Slot) -> Vec<Envelope<S>> {
+        let Role::Leader {
+            ballot: ours,
+            proposals,
+            ..
+        } = &mut self.role
+        else {
+            return vec![];
+        };
+        if ours != ballot {
+            return vec![];
+        }
+        let btree_map::Entry::Occupied(mut proposer) = proposals.entry(slot) else {
+            return vec![];
+        };
+        if !proposer.get_mut().on_accepted(from) {
+            return vec![];
+        }
+        let proposer = proposer.remove();
+        let entry = (slot, proposer.ballot().to_owned(), proposer.into_value());
+        let mut out = self.broadcast(PaxosMessage::Decided {
+            entries: vec![entry.to_owned()],
+        });
+        out.extend(self.decide(vec![entry]));
+        out
+    }
+
+    /// Learn chosen values, and apply the slots which no longer have a gap before them
+    fn decide(&mut self, entries: Vec<(Slot, Ballot, Value<S>)>) -> Vec<Envelope<S>> {
+        for (slot, ballot, value) in entries {
+            if slot >= self.applied {
+                self.chosen.insert(slot, (ballot, value));
+            }
+        }
+        let mut replies = vec![];
+        while let Some((ballot, value)) = self.chosen.get(&self.applied) {
+            let result = value.as_ref().map(|request| self.machine.apply(request));
+            let client = self.clients.remove(&self.applied);
+            self.applied += 1;
+            // another leader may have filled the slot we proposed the request in
+            let (Some(result), Some((proposed, client))) = (result, client) else {
+                continue;
+            };
+            if proposed == *ballot {
+                replies.push(match result {
+                    Ok(response) => client.reply(PaxosPayload::Response(response)),
+                    Err(e) => Self::reply_error(&client, &e),
+                });
+            }
+        }
+        if self.applied - self.compacted >= SNAPSHOT_INTERVAL {
+            self.chosen = self.chosen.split_off(&self.applied);
+            self.acceptors = self.acceptors.split_off(&self.applied);
+            self.compacted = self.applied;
+        }
+        replies
+    }
+
+    /// Snapshot of the state machine, if slots were forgotten since `slot`
+    fn snapshot_since(&self, slot: Slot) -> Result<Option<(Slot, serde_json::Value)>, Error> {
+        if slot >= self.compacted {
+            return Ok(None);
+        }
+        let state = serde_json::to_value(self.machine.snapshot()).map_err(|_| Error::Crash)?;
+        Ok(Some((self.applied, state)))
+    }
+
+    /// Replace our state with a snapshot with the slots below `slot` applied, forgetting them
+    fn restore(&mut self, slot: Slot, state: serde_json::Value) -> Result<(), Error> {
+        if slot <= self.applied {
+            return Ok(());
+        }
+        let state: S::Snapshot =
+            serde_json::from_value(state).map_err(|_| Error::MalformedRequest)?;
+        self.machine.restore(state);
+        self.applied = slot;
+        self.compacted = slot;
+        self.chosen = self.chosen.split_off(&slot);
+        self.acceptors = self.acceptors.split_off(&slot);
+        self.clients.retain(|client_slot, _| *client_slot >= slot);
+        Ok(())
+    }
+
+    fn reply_error(request: &Envelope<S>, error: &Error) -> Envelope<S> {
+        // the error body carries in_reply_to itself
+        let in_reply_to = request.body.msg_id.unwrap_or_default();
+        Message::new_reply_to(
+            request,
+            Body::new(PaxosPayload::Error(ErrorBody::new(error, in_reply_to))),
+        )
+    }
+
+    /// Propose a client request as the leader, or forward it to the leader
+    fn request(
+        &mut self,
+        message: &Envelope<S>,
+        request: &S::Command,
+    ) -> Result<Vec<Envelope<S>>, Error> {
+        match &mut self.role {
+            Role::Leader {
+                ballot, next_slot, ..
+            } => {
+                let slot = *next_slot;
+                *next_slot += 1;
+                self.clients
+                    .insert(slot, (ballot.to_owned(), message.to_owned()));
+                Ok(self.propose(slot, Some(request.to_owned())))
+            }
+            // nothing was proposed, so the client can retry elsewhere
+            Role::Candidate { .. } => Err(Error::TemporarilyUnavailable),
+            // requests forwarded by a peer are not forwarded again, lest they bounce between
+            // nodes which do not know the current leader
+            Role::Follower if self.peers.contains(&message.src) => {
+                Err(Error::TemporarilyUnavailable)
+            }
+            Role::Follower => {
+                let leader = self
+                    .leader()
+                    .filter(|leader| **leader != self.node_id())
+                    .ok_or(Error::TemporarilyUnavailable)?
+                    .to_owned();
+                let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+                let msg_id = handle.call(
+                    leader,
+                    &PaxosPayload::<S>::Request(request.to_owned()),
+                    rpc::DEFAULT_TIMEOUT,
+                )?;
+                self.forwarded.insert(msg_id, message.to_owned());
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn handle_paxos(
+        &mut self,
+        message: &Envelope<S>,
+        paxos: &PaxosMessage<S::Command>,
+    ) -> Result<Vec<Envelope<S>>, Error> {
+        let reply = |paxos| vec![message.reply(PaxosPayload::Paxos(paxos))];
+        match paxos {
+            PaxosMessage::Prepare { ballot, from_slot } => match self.promise(ballot)? {
+                Ok(()) => {
+                    self.reset_election_timer()?;
+                    Ok(reply(PaxosMessage::Promise {
+                        ballot: ballot.to_owned(),
+                        accepted: self.accepted_from(*from_slot),
+                        snapshot: self.snapshot_since(*from_slot)?,
+                    }))
+                }
+                Err(promised) => Ok(reply(PaxosMessage::Nack { promised })),
+            },
+            PaxosMessage::Promise {
+                ballot,
+                accepted,
+                snapshot,
+            } => self.on_promise(
+                &message.src,
+                ballot,
+                accepted.to_owned(),
+                snapshot.to_owned(),
+            ),
+            PaxosMessage::Accept {
+                ballot,
+                slot,
+                value,
+            } => match self.promise(ballot)? {
+                // a forgotten slot is chosen, and a leader can only propose its chosen value
+                Ok(()) if *slot < self.compacted => Ok(reply(PaxosMessage::Accepted {
+                    ballot: ballot.to_owned(),
+                    slot: *slot,
+                })),
+                Ok(()) => {
+                    let acceptor = self.acceptors.entry(*slot).or_default();
+                    if let Err(promised) = acceptor.accept(ballot, value.to_owned()) {
+                        return Ok(reply(PaxosMessage::Nack { promised }));
+                    }
+                    Ok(reply(PaxosMessage::Accepted {
+                        ballot: ballot.to_owned(),
+                        slot: *slot,
+                    }))
+                }
+                Err(promised) => Ok(reply(PaxosMessage::Nack { promised })),
+            },
+            PaxosMessage::Accepted { ballot, slot } => {
+                Ok(self.on_accepted(&message.src, ballot, *slot))
+            }
+            PaxosMessage::Nack { promised } => {
+                if self.ballot().is_some_and(|ours| ours < promised) {
+                    self.promised = Some(promised.to_owned());
+                    self.become_follower()?;
+                }
+                Ok(vec![])
+            }
+            PaxosMessage::Heartbeat { ballot } => match self.promise(ballot)? {
+                Ok(()) => {
+                    self.reset_election_timer()?;
+                    Ok(reply(PaxosMessage::HeartbeatOk {
+                        applied: self.applied,
+                    }))
+                }
+                Err(promised) => Ok(reply(PaxosMessage::Nack { promised })),
+            },
+            PaxosMessage::HeartbeatOk { applied } => {
+                if let Some((slot, state)) = self.snapshot_since(*applied)? {
+                    return Ok(reply(PaxosMessage::Snapshot { slot, state }));
+                }
+                let entries: Vec<_> = self
+                    .chosen
+                    .range(*applied..)
+                    .take(MAX_DECIDED_PER_MESSAGE)
+                    .map(|(slot, (ballot, value))| (*slot, ballot.to_owned(), value.to_owned()))
+                    .collect();
+                if entries.is_empty() {
+                    return Ok(vec![]);
+                }
+                Ok(reply(PaxosMessage::Decided { entries }))
+            }
+            PaxosMessage::Decided { entries } => Ok(self.decide(entries.to_owned())),
+            PaxosMessage::Snapshot { slot, state } => {
+                self.restore(*slot, state.to_owned())?;
+                Ok(self.decide(vec![]))
+            }
+        }
+    }
+
+    /// As the leader, send heartbeats and retransmit the proposals which are not chosen yet.
+    /// As a candidate, retransmit the prepare.
+    fn heartbeat(&self) -> Vec<Envelope<S>> {
+        match &self.role {
+            Role::Follower => vec![],
+            Role::Candidate { ballot, .. } => self.broadcast(PaxosMessage::Prepare {
+                ballot: ballot.to_owned(),
+                from_slot: self.applied,
+            }),
+            Role::Leader {
+                ballot, proposals, ..
+            } => {
+                let mut out = self.broadcast(PaxosMessage::Heartbeat {
+                    ballot: ballot.to_owned(),
+                });
+                for (slot, proposer) in proposals {
+                    out.extend(self.broadcast(PaxosMessage::Accept {
+                        ballot: ballot.to_owned(),
+                        slot: *slot,
+                        value: proposer.value().to_owned(),
+                    }));
+                }
+                out
+            }
+        }
+    }
+}
+
+impl<S: StateMachine> Actor for MultiPaxos<S> {
+    type MessagePayload = Body<PaxosPayload<S>>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: ActorID,
+        node_ids: Vec<ActorID>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.peers = node_ids.into_iter().filter(|n| *n != node_id).collect();
+        self.node_id = Some(node_id);
+        handle.schedule_every(HEARTBEAT_INTERVAL)?;
+        self.handle = Some(handle);
+        self.reset_election_timer()
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            PaxosPayload::Paxos(paxos) => self.handle_paxos(message, paxos),
+            PaxosPayload::Request(request) => self.request(message, request),
+            PaxosPayload::Response(_) | PaxosPayload::Error(_) => Ok(vec![]),
+        }
+    }
+
+    fn on_reply(&mut self, reply: RpcReply) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let Some(client) = self.forwarded.remove(&reply.request_id) else {
+            return Ok(vec![]);
+        };
+        match reply.decode::<Self::MessagePayload>() {
+            Ok(msg) => match msg.body.payload {
+                PaxosPayload::Response(response) => {
+                    Ok(vec![client.reply(PaxosPayload::Response(response))])
+                }
+                _ => Err(Error::MalformedRequest),
+            },
+            // a timeout is relayed as is, since the leader may have proposed the request
+            Err(e) => Ok(vec![Self::reply_error(&client, &e)]),
+        }
+    }
+
+    fn on_timer(&mut self, timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        if self.election_timer == Some(timer) {
+            self.election_timer = None;
+            return self.campaign();
+        }
+        Ok(self.heartbeat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{services::KvRequest, sim::Simulation, state_machine::KvStateMachine};
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    type Payload = PaxosPayload<KvStateMachine>;
+
+    fn decode(body: serde_json::Value) -> Result<Message<Body<Payload>>, Error> {
+        Message {
+            src: "c1".to_owned(),
+            dest: "n1".to_owned(),
+            body,
+        }
+        .decode()
+    }
+
+    #[test]
+    fn payloads_are_told_apart_by_type() {
+        let msg =
+            decode(json!({"type": "cas", "msg_id": 1, "key": 3, "from": 1, "to": 2})).unwrap();
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Request(KvRequest::Cas { .. })
+        ));
+        let msg = decode(json!({"type": "read_ok", "in_reply_to": 1, "value": 2})).unwrap();
+        assert!(matches!(msg.body.payload, Payload::Response(_)));
+        let heartbeat = json!({"type": "heartbeat", "ballot": {"round": 2, "node": "n2"}});
+        let msg = decode(heartbeat).unwrap();
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Paxos(PaxosMessage::Heartbeat { .. })
+        ));
+    }
+
+    #[test]
+    fn errors_are_decoded() {
+        let error = json!({"type": "error", "in_reply_to": 4, "code": 11, "text": "no leader"});
+        let msg = decode(error).unwrap();
+        assert_eq!(msg.body.in_reply_to, Some(4));
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Error(ErrorBody { code: 11, .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_types_are_not_supported() {
+        let e = decode(json!({"type": "generate", "msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::NotSupported));
+    }
+
+    #[test]
+    fn known_types_with_bad_fields_are_malformed() {
+        let e = decode(json!({"type": "read", "msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+        let e = decode(json!({"type": "accept", "slot": 1})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+    }
+
+    fn ballot(round: u64, node: &str) -> Ballot {
+        Ballot {
+            round,
+            node: node.to_owned(),
+        }
+    }
+
+    #[test]
+    fn acceptors_ignore_ballots_below_their_promise() {
+        let mut acceptor = Acceptor::default();
+        assert_eq!(acceptor.prepare(&ballot(1, "n0")), Ok(None));
+        assert_eq!(acceptor.accept(&ballot(1, "n0"), 'x'), Ok(()));
+        assert_eq!(
+            acceptor.prepare(&ballot(2, "n1")),
+            Ok(Some((ballot(1, "n0"), 'x')))
+        );
+        assert_eq!(acceptor.accept(&ballot(1, "n0"), 'y'), Err(ballot(2, "n1")));
+        assert_eq!(acceptor.prepare(&ballot(1, "n2")), Err(ballot(2, "n1")));
+        assert_eq!(acceptor.accepted(), Some(&(ballot(1, "n0"), 'x')));
+        assert_eq!(acceptor.promised(), Some(&ballot(2, "n1")));
+    }
+
+    #[test]
+    fn proposers_adopt_the_value_accepted_with_the_highest_ballot() {
+        let mut proposer = Proposer::new(ballot(3, "n0"), 'z', 2);
+        assert!(!proposer.on_promise(&"n1".to_owned(), Some((ballot(1, "n1"), 'x'))));
+        assert!(proposer.on_promise(&"n2".to_owned(), Some((ballot(2, "n2"), 'y'))));
+        assert_eq!(*proposer.value(), 'y');
+        // a quorum is reported once
+        assert!(!proposer.on_promise(&"n3".to_owned(), None));
+        assert!(!proposer.on_accepted(&"n1".to_owned()));
+        assert!(!proposer.on_accepted(&"n1".to_owned()));
+        assert!(proposer.on_accepted(&"n2".to_owned()));
+        assert_eq!(proposer.into_value(), 'y');
+    }
+
+    /// Run both phases of `proposer` against `acceptors`, returning whether its value was chosen
+    fn run(proposer: &mut Proposer<char>, acceptors: &mut [(ActorID, Acceptor<char>)]) -> bool {
+        let mut promised = false;
+        for (node, acceptor) in acceptors.iter_mut() {
+            if let Ok(accepted) = acceptor.prepare(proposer.ballot()) {
+                promised |= proposer.on_promise(node, accepted);
+            }
+        }
+        if !promised {
+            return false;
+        }
+        let mut chosen = false;
+        for (node, acceptor) in acceptors.iter_mut() {
+            if acceptor
+                .accept(proposer.ballot(), *proposer.value())
+                .is_ok()
+            {
+                chosen |= proposer.on_accepted(node);
+            }
+        }
+        chosen
+    }
+
+    #[test]
+    fn a_chosen_value_stays_chosen() {
+        let mut acceptors: Vec<(ActorID, Acceptor<char>)> = ["n0", "n1", "n2"]
+            .iter()
+            .map(|node| (node.to_string(), Acceptor::default()))
+            .collect();
+        // the first proposer only reaches a majority
+        let mut first = Proposer::new(ballot(1, "n0"), 'x', 2);
+        assert!(run(&mut first, &mut acceptors[..2]));
+        // a later proposer reaching the other acceptor learns the chosen value from the quorum
+        let mut second = Proposer::new(ballot(2, "n2"), 'y', 2);
+        assert!(run(&mut second, &mut acceptors[1..]));
+        assert_eq!(second.into_value(), 'x');
+        // and the first ballot can no longer be accepted
+        assert!(acceptors[2].1.accept(&ballot(1, "n0"), 'x').is_err());
+    }
+
+    #[test]
+    fn forwarded_requests_are_not_forwarded_again() {
+        let mut paxos = MultiPaxos::<KvStateMachine>::default();
+        let (tx, _rx) = mpsc::channel();
+        let nodes = vec!["n0".to_owned(), "n1".to_owned(), "n2".to_owned()];
+        paxos
+            .init(Handle::new("n0".to_owned(), tx), "n0".to_owned(), nodes)
+            .unwrap();
+        paxos.promised = Some(ballot(1, "n1"));
+        let request = |src: &str| Message {
+            src: src.to_owned(),
+            dest: "n0".to_owned(),
+            body: Body::new(Payload::Request(KvRequest::Read { key: json!(1) })),
+        };
+
+        assert!(paxos.receive(&request("c1")).unwrap().is_empty());
+        assert_eq!(paxos.forwarded.len(), 1);
+        let e = paxos.receive(&request("n2")).unwrap_err();
+        assert!(matches!(e, Error::TemporarilyUnavailable));
+        assert_eq!(paxos.forwarded.len(), 1);
+    }
+
+    /// A leader among `nodes`
+    fn leader(sim: &Simulation<MultiPaxos<KvStateMachine>>, nodes: &[ActorID]) -> ActorID {
+        nodes
+            .iter()
+            .find(|node| sim.actor(node).unwrap().is_leader())
+            .unwrap()
+            .to_owned()
+    }
+
+    fn write(sim: &mut Simulation<MultiPaxos<KvStateMachine>>, node: &str, key: u64) {
+        let write = KvRequest::Write {
+            key: json!(key),
+            value: json!(key),
+        };
+        sim.request("c1", node, &Body::new(Payload::Request(write)));
+        sim.run_for(Duration::from_millis(10));
+    }
+
+    #[test]
+    fn lagging_nodes_catch_up_from_a_snapshot() {
+        let mut sim =
+            Simulation::<MultiPaxos<KvStateMachine>>::new(3, 4, Default::default()).unwrap();
+        sim.run_for(Duration::from_secs(2));
+        let nodes = sim.node_ids();
+        let first = leader(&sim, &nodes);
+        let lagging = nodes
+            .iter()
+            .find(|node| **node != first)
+            .unwrap()
+            .to_owned();
+        let others: Vec<ActorID> = nodes.iter().filter(|n| **n != lagging).cloned().collect();
+        sim.partition(&[others, vec![lagging.to_owned()]]);
+        let writes = 2 * SNAPSHOT_INTERVAL;
+        for key in 0..writes {
+            write(&mut sim, &first, key);
+        }
+        sim.run_for(Duration::from_secs(1));
+        let paxos = sim.actor(&first).unwrap();
+        assert!(paxos.is_leader());
+        assert!(paxos.compacted > 0);
+        assert!(paxos.chosen.len() as Slot <= SNAPSHOT_INTERVAL);
+        assert!(paxos.acceptors.len() as Slot <= SNAPSHOT_INTERVAL);
+
+        // the lagging node either campaigns, and learns the snapshot from a promise, or is sent
+        // it by the new leader
+        let rest: Vec<ActorID> = nodes.iter().filter(|n| **n != first).cloned().collect();
+        sim.partition(&[rest.to_owned(), vec![first.to_owned()]]);
+        sim.run_for(Duration::from_secs(2));
+        let second = leader(&sim, &rest);
+        write(&mut sim, &second, writes);
+        sim.heal();
+        sim.run_for(Duration::from_secs(2));
+        let expected = sim.actor(&second).unwrap().machine.snapshot();
+        assert_eq!(expected.len(), writes as usize + 1);
+        for node in &nodes {
+            let paxos = sim.actor(node).unwrap();
+            assert_eq!(paxos.machine.snapshot(), expected);
+            assert!(paxos.compacted > 0);
+        }
+    }
+}
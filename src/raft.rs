@@ -0,0 +1,919 @@
+//! Raft consensus, replicating the commands of a [`StateMachine`] across the nodes.
+//!
+//! Taken from [the Raft paper](https://raft.github.io/raft.pdf): a leader is elected for every
+//! term, appends the client requests it receives to its log, and replicates the log to the other
+//! nodes. An entry is committed once a majority holds it, and committed entries are applied to
+//! the state machine in log order on every node. Requests sent to a follower are forwarded to the
+//! leader, and the client is answered once its request has been applied.
+//!
+//! Applied entries are dropped from the log every [`SNAPSHOT_INTERVAL`] entries. A follower
+//! missing entries which were dropped is sent a snapshot of the state machine instead.
+use crate::{
+    actor::{Actor, ActorID},
+    errors::{Error, ErrorBody},
+    message::{supports_type, Body, Message, MessageID},
+    rpc::{self, RpcReply},
+    runtime::Handle,
+    state_machine::StateMachine,
+    timer::TimerId,
+};
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+/// How often the leader sends `AppendEntries`, even when it has nothing new
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// A follower which has not heard from a leader for a random time in this range starts an election
+pub const ELECTION_TIMEOUT: RangeInclusive<Duration> =
+    Duration::from_millis(300)..=Duration::from_millis(600);
+/// Cap on the entries sent in a single `AppendEntries`
+pub const MAX_ENTRIES_PER_APPEND: usize = 64;
+/// Number of applied entries kept in the log before they are dropped
+pub const SNAPSHOT_INTERVAL: LogIndex = 256;
+
+/// Index of an entry in the log. The first entry has index 1, and 0 stands for the empty log.
+pub type LogIndex = u64;
+pub type Term = u64;
+
+/// A client command, with the term of the leader which appended it. Leaders start their term
+/// with an entry without any command, which commits the entries of the terms before it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry<R> {
+    pub term: Term,
+    pub command: Option<R>,
+}
+
+/// Messages exchanged between the nodes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RaftMessage<R> {
+    RequestVote {
+        term: Term,
+        candidate_id: ActorID,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    RequestVoteOk {
+        term: Term,
+        vote_granted: bool,
+    },
+    /// Entries following `prev_log_index`. Sent without any as a heartbeat.
+    AppendEntries {
+        term: Term,
+        leader_id: ActorID,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry<R>>,
+        leader_commit: LogIndex,
+    },
+    /// On success, `match_index` is the last entry the follower now shares with the leader.
+    /// On failure, it is a hint of where their logs may match.
+    /// Also answers `InstallSnapshot`.
+    AppendEntriesOk {
+        term: Term,
+        success: bool,
+        match_index: LogIndex,
+    },
+    /// Snapshot of the state machine with every entry up to `last_included_index` applied, sent
+    /// instead of entries the leader no longer holds
+    InstallSnapshot {
+        term: Term,
+        leader_id: ActorID,
+        last_included_index: LogIndex,
+        last_included_term: Term,
+        snapshot: Value,
+    },
+}
+
+/// Payload of every message a [`Raft`] node sends or receives: Raft RPCs between the nodes,
+/// and the commands and outputs of the state machine, as the requests and replies of clients.
+/// `Error` relays the errors the leader answered with to the clients of a follower.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+#[serde(bound = "")]
+pub enum RaftPayload<S: StateMachine> {
+    Raft(RaftMessage<S::Command>),
+    Request(S::Command),
+    Response(S::Output),
+    Error(ErrorBody),
+}
+
+/// Variants are told apart by their `type` tag rather than by trying each in turn, so that a
+/// type none of them has is reported as an unknown variant, and a body which does not fit the
+/// variant of its type as malformed.
+impl<'de, S: StateMachine> Deserialize<'de> for RaftPayload<S> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let body = Value::deserialize(deserializer)?;
+        let message_type = body
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| de::Error::missing_field("type"))?
+            .to_owned();
+        let payload = if message_type == "error" {
+            serde_json::from_value(body).map(RaftPayload::Error)
+        } else if supports_type::<RaftMessage<S::Command>>(&message_type) {
+            serde_json::from_value(body).map(RaftPayload::Raft)
+        } else if supports_type::<S::Command>(&message_type) {
+            serde_json::from_value(body).map(RaftPayload::Request)
+        } else if supports_type::<S::Output>(&message_type) {
+            serde_json::from_value(body).map(RaftPayload::Response)
+        } else {
+            return Err(de::Error::unknown_variant(&message_type, &[]));
+        };
+        payload.map_err(de::Error::custom)
+    }
+}
+
+/// Derived, it would require the state machine itself to be `Clone`
+impl<S: StateMachine> Clone for RaftPayload<S> {
+    fn clone(&self) -> Self {
+        match self {
+            RaftPayload::Raft(message) => RaftPayload::Raft(message.to_owned()),
+            RaftPayload::Request(request) => RaftPayload::Request(request.to_owned()),
+            RaftPayload::Response(response) => RaftPayload::Response(response.to_owned()),
+            RaftPayload::Error(error) => RaftPayload::Error(error.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<ActorID>,
+    },
+    Leader {
+        /// Next entry to send to each peer
+        next_index: HashMap<ActorID, LogIndex>,
+        /// Last entry known to be held by each peer
+        match_index: HashMap<ActorID, LogIndex>,
+    },
+}
+
+/// A node of a Raft cluster replicating `S`
+pub struct Raft<S: StateMachine> {
+    node_id: Option<ActorID>,
+    peers: Vec<ActorID>,
+    handle: Option<Handle<Body<RaftPayload<S>>>>,
+    machine: S,
+    role: Role,
+    current_term: Term,
+    voted_for: Option<ActorID>,
+    /// Node we last heard from as the leader of `current_term`
+    leader_id: Option<ActorID>,
+    /// Entries following `snapshot_index`
+    log: Vec<Entry<S::Command>>,
+    /// Last entry dropped from the log, and its term
+    snapshot_index: LogIndex,
+    snapshot_term: Term,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    election_timer: Option<TimerId>,
+    /// Requests we appended as the leader, answered once applied, by log index
+    clients: HashMap<LogIndex, Message<Body<RaftPayload<S>>>>,
+    /// Requests forwarded to the leader, by msg_id of the forwarded request
+    forwarded: HashMap<MessageID, Message<Body<RaftPayload<S>>>>,
+}
+
+impl<S: StateMachine> Default for Raft<S> {
+    fn default() -> Self {
+        Self {
+            node_id: None,
+            peers: vec![],
+            handle: None,
+            machine: S::default(),
+            role: Role::Follower,
+            current_term: 0,
+            voted_for: None,
+            leader_id: None,
+            log: vec![],
+            snapshot_index: 0,
+            snapshot_term: 0,
+            commit_index: 0,
+            last_applied: 0,
+            election_timer: None,
+            clients: HashMap::new(),
+            forwarded: HashMap::new(),
+        }
+    }
+}
+
+impl<S: StateMachine> Raft<S> {
+    #[inline(always)]
+    pub fn node_id(&self) -> String {
+        self.node_id.as_ref().unwrap().to_owned()
+    }
+
+    /// Node currently believed to be the leader, if any
+    pub fn leader(&self) -> Option<&ActorID> {
+        self.leader_id.as_ref()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn term(&self) -> Term {
+        self.current_term
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    /// The state machine, with every committed command applied
+    pub fn state_machine(&self) -> &S {
+        &self.machine
+    }
+
+    fn last_log_index(&self) -> LogIndex {
+        self.snapshot_index + self.log.len() as LogIndex
+    }
+
+    /// Entry at `index`, unless it was dropped
+    fn entry(&self, index: LogIndex) -> Option<&Entry<S::Command>> {
+        let offset = index.checked_sub(self.snapshot_index + 1)?;
+        self.log.get(offset as usize)
+    }
+
+    fn term_at(&self, index: LogIndex) -> Option<Term> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn last_log_term(&self) -> Term {
+        self.term_at(self.last_log_index()).unwrap_or_default()
+    }
+
+    fn majority(&self) -> usize {
+        let cluster_size = self.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn message(
+        &self,
+        dest: &ActorID,
+        message: RaftMessage<S::Command>,
+    ) -> Message<Body<RaftPayload<S>>> {
+        Message {
+            src: self.node_id(),
+            dest: dest.to_owned(),
+            body: Body::new(RaftPayload::Raft(message)),
+        }
+    }
+
+    /// Start waiting for a leader again, for a new random timeout
+    fn reset_election_timer(&mut self) -> Result<(), Error> {
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        if let Some(timer) = self.election_timer.take() {
+            handle.cancel(timer)?;
+        }
+        let timeout = handle.rng().gen_range(ELECTION_TIMEOUT);
+        self.election_timer = Some(handle.schedule_once(timeout)?);
+        Ok(())
+    }
+
+    /// Move to `term` if it is newer than ours, stepping down if we were leading or campaigning
+    fn observe_term(&mut self, term: Term) -> Result<(), Error> {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.leader_id = None;
+            self.become_follower()?;
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self) -> Result<(), Error> {
+        if let Role::Leader { .. } = self.role {
+            // our entries may be overwritten: their clients time out, as they may still commit
+            self.clients.clear();
+            self.reset_election_timer()?;
+        }
+        if !matches!(self.role, Role::Follower) {
+            eprintln!(
+                "{} is now a follower in term {}",
+                self.node_id(),
+                self.current_term
+            );
+        }
+        self.role = Role::Follower;
+        Ok(())
+    }
+
+    /// Stand for election in a new term
+    fn campaign(&mut self) -> Result<Vec<Message<Body<RaftPayload<S>>>>, Error> {
+        self.current_term += 1;
+        self.voted_for = Some(self.node_id());
+        self.leader_id = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.node_id()]),
+        };
+        eprintln!(
+            "{} campaigns for term {}",
+            self.node_id(),
+            self.current_term
+        );
+        self.reset_election_timer()?;
+        if self.majority() == 1 {
+            return Ok(self.become_leader());
+        }
+        let request = RaftMessage::RequestVote {
+            term: self.current_term,
+            candidate_id: self.node_id(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        Ok(self
+            .peers
+            .iter()
+            .map(|peer| self.message(peer, request.to_owned()))
+            .collect())
+    }
+
+    fn become_leader(&mut self) -> Vec<Message<Body<RaftPayload<S>>>> {
+        eprintln!(
+            "{} is the leader of term {}",
+            self.node_id(),
+            self.current_term
+        );
+        // entries of older terms are only committed along with one of ours
+        self.log.push(Entry {
+            term: self.current_term,
+            command: None,
+        });
+        let next = self.last_log_index();
+        self.role = Role::Leader {
+            next_index: self.peers.iter().map(|p| (p.to_owned(), next)).collect(),
+            match_index: self.peers.iter().map(|p| (p.to_owned(), 0)).collect(),
+        };
+        self.leader_id = Some(self.node_id());
+        if let (Some(handle), Some(timer)) = (self.handle.as_ref(), self.election_timer.take()) {
+            let _ = handle.cancel(timer);
+        }
+        self.advance_commit_index();
+        let mut out = self.apply_committed();
+        out.extend(self.heartbeat());
+        out
+    }
+
+    /// `AppendEntries` carrying what `peer` is missing, up to `MAX_ENTRIES_PER_APPEND` entries
+    fn append_entries(&self, peer: &ActorID) -> Option<Message<Body<RaftPayload<S>>>> {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return None;
+        };
+        let prev_log_index = next_index.get(peer).copied().unwrap_or(1) - 1;
+        if prev_log_index < self.snapshot_index {
+            return self.install_snapshot(peer);
+        }
+        let entries = self
+            .log
+            .iter()
+            .skip((prev_log_index - self.snapshot_index) as usize)
+            .take(MAX_ENTRIES_PER_APPEND)
+            .cloned()
+            .collect();
+        Some(self.message(
+            peer,
+            RaftMessage::AppendEntries {
+                term: self.current_term,
+                leader_id: self.node_id(),
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap_or_default(),
+                entries,
+                leader_commit: self.commit_index,
+            },
+        ))
+    }
+
+    /// `InstallSnapshot` of the state machine as of the last entry applied
+    fn install_snapshot(&self, peer: &ActorID) -> Option<Message<Body<RaftPayload<S>>>> {
+        let snapshot = serde_json::to_value(self.machine.snapshot()).ok()?;
+        Some(self.message(
+            peer,
+            RaftMessage::InstallSnapshot {
+                term: self.current_term,
+                leader_id: self.node_id(),
+                last_included_index: self.last_applied,
+                last_included_term: self.term_at(self.last_applied)?,
+                snapshot,
+            },
+        ))
+    }
+
+    /// Send every peer the entries it is missing, or an empty heartbeat
+    fn heartbeat(&self) -> Vec<Message<Body<RaftPayload<S>>>> {
+        self.peers
+            .iter()
+            .filter_map(|peer| self.append_entries(peer))
+            .collect()
+    }
+
+    /// Commit the last entry of our term held by a majority, and every entry before it.
+    /// Entries of older terms are never committed by counting replicas: their index could be
+    /// overwritten by a leader which never saw them.
+    fn advance_commit_index(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let mut indexes: Vec<LogIndex> = match_index.values().copied().collect();
+        indexes.push(self.last_log_index());
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let replicated = indexes[self.majority() - 1];
+        if replicated > self.commit_index && self.term_at(replicated) == Some(self.current_term) {
+            self.commit_index = replicated;
+        }
+    }
+
+    /// Apply the entries committed since last time, answering the clients waiting on them
+    fn apply_committed(&mut self) -> Vec<Message<Body<RaftPayload<S>>>> {
+        let mut replies = vec![];
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let offset = (self.last_applied - self.snapshot_index - 1) as usize;
+            let Some(command) = &self.log[offset].command else {
+                continue;
+            };
+            let result = self.machine.apply(command);
+            let Some(client) = self.clients.remove(&self.last_applied) else {
+                continue;
+            };
+            replies.push(match result {
+                Ok(response) => client.reply(RaftPayload::Response(response)),
+                Err(e) => Self::reply_error(&client, &e),
+            });
+        }
+        self.compact();
+        replies
+    }
+
+    /// Drop the applied entries once there are `SNAPSHOT_INTERVAL` of them
+    fn compact(&mut self) {
+        if self.last_applied - self.snapshot_index < SNAPSHOT_INTERVAL {
+            return;
+        }
+        self.snapshot_term = self.term_at(self.last_applied).unwrap_or_default();
+        self.log
+            .drain(..(self.last_applied - self.snapshot_index) as usize);
+        self.snapshot_index = self.last_applied;
+    }
+
+    /// Replace our state with a snapshot of the leader's, keeping the entries which follow it
+    fn restore(
+        &mut self,
+        last_included_index: LogIndex,
+        last_included_term: Term,
+        snapshot: &Value,
+    ) -> Result<(), Error> {
+        if last_included_index <= self.snapshot_index {
+            return Ok(());
+        }
+        let snapshot: S::Snapshot =
+            serde_json::from_value(snapshot.to_owned()).map_err(|_| Error::MalformedRequest)?;
+        if self.term_at(last_included_index) == Some(last_included_term) {
+            self.log
+                .drain(..(last_included_index - self.snapshot_index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = last_included_index;
+        self.snapshot_term = last_included_term;
+        if self.last_applied < last_included_index {
+            self.machine.restore(snapshot);
+            self.last_applied = last_included_index;
+        }
+        self.commit_index = self.commit_index.max(last_included_index);
+        Ok(())
+    }
+
+    fn reply_error(
+        request: &Message<Body<RaftPayload<S>>>,
+        error: &Error,
+    ) -> Message<Body<RaftPayload<S>>> {
+        // the error body carries in_reply_to itself
+        let in_reply_to = request.body.msg_id.unwrap_or_default();
+        Message::new_reply_to(
+            request,
+            Body::new(RaftPayload::Error(ErrorBody::new(error, in_reply_to))),
+        )
+    }
+
+    /// Append a client request as the leader, or forward it to the leader
+    fn request(
+        &mut self,
+        message: &Message<Body<RaftPayload<S>>>,
+        request: &S::Command,
+    ) -> Result<Vec<Message<Body<RaftPayload<S>>>>, Error> {
+        if self.is_leader() {
+            self.log.push(Entry {
+                term: self.current_term,
+                command: Some(request.to_owned()),
+            });
+            self.clients
+                .insert(self.last_log_index(), message.to_owned());
+            self.advance_commit_index();
+            let mut out = self.apply_committed();
+            out.extend(self.heartbeat());
+            return Ok(out);
+        }
+        // nothing was appended, so the client can retry elsewhere. Requests forwarded by a peer
+        // are not forwarded again, lest they bounce between nodes with stale leaders.
+        if self.peers.contains(&message.src) {
+            return Err(Error::TemporarilyUnavailable);
+        }
+        let leader = self
+            .leader_id
+            .to_owned()
+            .ok_or(Error::TemporarilyUnavailable)?;
+        let handle = self.handle.as_ref().ok_or(Error::TemporarilyUnavailable)?;
+        let msg_id = handle.call(
+            leader,
+            &RaftPayload::<S>::Request(request.to_owned()),
+            rpc::DEFAULT_TIMEOUT,
+        )?;
+        self.forwarded.insert(msg_id, message.to_owned());
+        Ok(vec![])
+    }
+
+    fn handle_raft(
+        &mut self,
+        message: &Message<Body<RaftPayload<S>>>,
+        raft: &RaftMessage<S::Command>,
+    ) -> Result<Vec<Message<Body<RaftPayload<S>>>>, Error> {
+        match raft {
+            RaftMessage::RequestVote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => {
+                self.observe_term(*term)?;
+                let up_to_date = (*last_log_term, *last_log_index)
+                    >= (self.last_log_term(), self.last_log_index());
+                let vote_granted = *term == self.current_term
+                    && up_to_date
+                    && self.voted_for.as_ref().is_none_or(|v| v == candidate_id);
+                if vote_granted {
+                    self.voted_for = Some(candidate_id.to_owned());
+                    self.reset_election_timer()?;
+                }
+                Ok(vec![message.reply(RaftPayload::Raft(
+                    RaftMessage::RequestVoteOk {
+                        term: self.current_term,
+                        vote_granted,
+                    },
+                ))])
+            }
+            RaftMessage::RequestVoteOk { term, vote_granted } => {
+                self.observe_term(*term)?;
+                if *term != self.current_term || !vote_granted {
+                    return Ok(vec![]);
+                }
+                let majority = self.majority();
+                match &mut self.role {
+                    Role::Candidate { votes } => {
+                        votes.insert(message.src.to_owned());
+                        if votes.len() >= majority {
+                            return Ok(self.become_leader());
+                        }
+                        Ok(vec![])
+                    }
+                    _ => Ok(vec![]),
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                self.observe_term(*term)?;
+                if *term < self.current_term {
+                    return Ok(vec![message.reply(RaftPayload::Raft(
+                        RaftMessage::AppendEntriesOk {
+                            term: self.current_term,
+                            success: false,
+                            match_index: 0,
+                        },
+                    ))]);
+                }
+                // a candidate of this term lost the election
+                self.become_follower()?;
+                self.leader_id = Some(leader_id.to_owned());
+                self.reset_election_timer()?;
+
+                // entries we dropped are committed, so they match the leader's
+                if *prev_log_index >= self.snapshot_index
+                    && self.term_at(*prev_log_index) != Some(*prev_log_term)
+                {
+                    let hint = self.last_log_index().min(prev_log_index.saturating_sub(1));
+                    return Ok(vec![message.reply(RaftPayload::Raft(
+                        RaftMessage::AppendEntriesOk {
+                            term: self.current_term,
+                            success: false,
+                            match_index: hint,
+                        },
+                    ))]);
+                }
+                for (i, entry) in entries.iter().enumerate() {
+                    let index = prev_log_index + 1 + i as LogIndex;
+                    if index <= self.snapshot_index {
+                        continue;
+                    }
+                    match self.term_at(index) {
+                        Some(term) if term == entry.term => continue,
+                        // a conflicting entry is never committed, so it and what follows are dropped
+                        Some(_) => self
+                            .log
+                            .truncate((index - self.snapshot_index) as usize - 1),
+                        None => {}
+                    }
+                    self.log.push(entry.to_owned());
+                }
+                let match_index =
+                    (prev_log_index + entries.len() as LogIndex).max(self.snapshot_index);
+                self.commit_index = self.commit_index.max((*leader_commit).min(match_index));
+                let mut out = self.apply_committed();
+                out.push(
+                    message.reply(RaftPayload::Raft(RaftMessage::AppendEntriesOk {
+                        term: self.current_term,
+                        success: true,
+                        match_index,
+                    })),
+                );
+                Ok(out)
+            }
+            RaftMessage::InstallSnapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                snapshot,
+            } => {
+                self.observe_term(*term)?;
+                if *term < self.current_term {
+                    return Ok(vec![message.reply(RaftPayload::Raft(
+                        RaftMessage::AppendEntriesOk {
+                            term: self.current_term,
+                            success: false,
+                            match_index: 0,
+                        },
+                    ))]);
+                }
+                self.become_follower()?;
+                self.leader_id = Some(leader_id.to_owned());
+                self.reset_election_timer()?;
+                self.restore(*last_included_index, *last_included_term, snapshot)?;
+                let mut out = self.apply_committed();
+                out.push(
+                    message.reply(RaftPayload::Raft(RaftMessage::AppendEntriesOk {
+                        term: self.current_term,
+                        success: true,
+                        match_index: *last_included_index,
+                    })),
+                );
+                Ok(out)
+            }
+            RaftMessage::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                self.observe_term(*term)?;
+                if *term != self.current_term {
+                    return Ok(vec![]);
+                }
+                let Role::Leader {
+                    next_index,
+                    match_index: matched,
+                } = &mut self.role
+                else {
+                    return Ok(vec![]);
+                };
+                let peer = message.src.to_owned();
+                let next = next_index.entry(peer.to_owned()).or_insert(1);
+                if *success {
+                    let matched = matched.entry(peer).or_default();
+                    *matched = (*matched).max(*match_index);
+                    *next = (*next).max(*matched + 1);
+                    self.advance_commit_index();
+                    return Ok(self.apply_committed());
+                }
+                // back up past the mismatch, and try again right away
+                *next = next.saturating_sub(1).min(match_index + 1).max(1);
+                Ok(self.append_entries(&message.src).into_iter().collect())
+            }
+        }
+    }
+}
+
+impl<S: StateMachine> Actor for Raft<S> {
+    type MessagePayload = Body<RaftPayload<S>>;
+
+    fn init(
+        &mut self,
+        handle: Handle<Self::MessagePayload>,
+        node_id: ActorID,
+        node_ids: Vec<ActorID>,
+    ) -> Result<(), Error> {
+        eprintln!("Initialized node {}", node_id);
+        self.peers = node_ids.into_iter().filter(|n| *n != node_id).collect();
+        self.node_id = Some(node_id);
+        handle.schedule_every(HEARTBEAT_INTERVAL)?;
+        self.handle = Some(handle);
+        self.reset_election_timer()
+    }
+
+    fn receive(
+        &mut self,
+        message: &Message<Self::MessagePayload>,
+    ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        match &message.body.payload {
+            RaftPayload::Raft(raft) => self.handle_raft(message, raft),
+            RaftPayload::Request(request) => self.request(message, request),
+            RaftPayload::Response(_) | RaftPayload::Error(_) => Ok(vec![]),
+        }
+    }
+
+    fn on_reply(&mut self, reply: RpcReply) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        let Some(client) = self.forwarded.remove(&reply.request_id) else {
+            return Ok(vec![]);
+        };
+        match reply.decode::<Self::MessagePayload>() {
+            Ok(msg) => match msg.body.payload {
+                RaftPayload::Response(response) => {
+                    Ok(vec![client.reply(RaftPayload::Response(response))])
+                }
+                _ => Err(Error::MalformedRequest),
+            },
+            // a timeout is relayed as is, since the leader may have appended the request
+            Err(e) => Ok(vec![Self::reply_error(&client, &e)]),
+        }
+    }
+
+    fn on_timer(&mut self, timer: TimerId) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+        if self.election_timer == Some(timer) {
+            self.election_timer = None;
+            return self.campaign();
+        }
+        Ok(self.heartbeat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{services::KvRequest, sim::Simulation, state_machine::KvStateMachine};
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    type Payload = RaftPayload<KvStateMachine>;
+
+    fn decode(body: Value) -> Result<Message<Body<Payload>>, Error> {
+        Message {
+            src: "c1".to_owned(),
+            dest: "n1".to_owned(),
+            body,
+        }
+        .decode()
+    }
+
+    #[test]
+    fn payloads_are_told_apart_by_type() {
+        let msg = decode(json!({"type": "read", "msg_id": 1, "key": 3})).unwrap();
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Request(KvRequest::Read { .. })
+        ));
+        let msg = decode(json!({"type": "write_ok", "in_reply_to": 1})).unwrap();
+        assert!(matches!(msg.body.payload, Payload::Response(_)));
+        let vote = json!({
+            "type": "request_vote", "term": 2, "candidate_id": "n2",
+            "last_log_index": 0, "last_log_term": 0,
+        });
+        let msg = decode(vote).unwrap();
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Raft(RaftMessage::RequestVote { term: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn errors_are_decoded() {
+        let error = json!({"type": "error", "in_reply_to": 4, "code": 11, "text": "no leader"});
+        let msg = decode(error).unwrap();
+        assert_eq!(msg.body.in_reply_to, Some(4));
+        assert!(matches!(
+            msg.body.payload,
+            Payload::Error(ErrorBody { code: 11, .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_types_are_not_supported() {
+        let e = decode(json!({"type": "generate", "msg_id": 1})).unwrap_err();
+        assert!(matches!(e, Error::NotSupported));
+    }
+
+    #[test]
+    fn known_types_with_bad_fields_are_malformed() {
+        let e = decode(json!({"type": "write", "msg_id": 1, "key": 3})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+        let e = decode(json!({"type": "append_entries", "term": 1})).unwrap_err();
+        assert!(matches!(e, Error::MalformedRequest));
+    }
+
+    #[test]
+    fn leaders_commit_a_no_op_of_their_term() {
+        let mut sim = Simulation::<Raft<KvStateMachine>>::new(3, 1, Default::default()).unwrap();
+        sim.run_for(Duration::from_secs(2));
+        let nodes = sim.node_ids();
+        let leader = nodes
+            .iter()
+            .map(|node| sim.actor(node).unwrap())
+            .find(|raft| raft.is_leader())
+            .unwrap();
+        let last = leader.log.last().unwrap();
+        assert!(last.command.is_none());
+        assert_eq!(last.term, leader.term());
+        // committed everywhere, without any client request
+        for node in &nodes {
+            let raft = sim.actor(node).unwrap();
+            assert_eq!(raft.commit_index(), leader.last_log_index());
+        }
+    }
+
+    #[test]
+    fn forwarded_requests_are_not_forwarded_again() {
+        let mut raft = Raft::<KvStateMachine>::default();
+        let (tx, _rx) = mpsc::channel();
+        let nodes = vec!["n0".to_owned(), "n1".to_owned(), "n2".to_owned()];
+        raft.init(Handle::new("n0".to_owned(), tx), "n0".to_owned(), nodes)
+            .unwrap();
+        raft.leader_id = Some("n1".to_owned());
+        let request = |src: &str| Message {
+            src: src.to_owned(),
+            dest: "n0".to_owned(),
+            body: Body::new(Payload::Request(KvRequest::Read { key: json!(1) })),
+        };
+
+        assert!(raft.receive(&request("c1")).unwrap().is_empty());
+        assert_eq!(raft.forwarded.len(), 1);
+        let e = raft.receive(&request("n2")).unwrap_err();
+        assert!(matches!(e, Error::TemporarilyUnavailable));
+        assert_eq!(raft.forwarded.len(), 1);
+    }
+
+    #[test]
+    fn lagging_followers_catch_up_from_a_snapshot() {
+        let mut sim = Simulation::<Raft<KvStateMachine>>::new(3, 2, Default::default()).unwrap();
+        sim.run_for(Duration::from_secs(2));
+        let nodes = sim.node_ids();
+        let leader = nodes
+            .iter()
+            .find(|node| sim.actor(node).unwrap().is_leader())
+            .unwrap()
+            .to_owned();
+        let lagging = nodes
+            .iter()
+            .find(|node| **node != leader)
+            .unwrap()
+            .to_owned();
+        let others: Vec<ActorID> = nodes.iter().filter(|n| **n != lagging).cloned().collect();
+        sim.partition(&[others, vec![lagging.to_owned()]]);
+
+        let writes = 2 * SNAPSHOT_INTERVAL;
+        for key in 0..writes {
+            let write = KvRequest::Write {
+                key: json!(key),
+                value: json!(key),
+            };
+            sim.request("c1", &leader, &Body::new(Payload::Request(write)));
+            sim.run_for(Duration::from_millis(10));
+        }
+        sim.run_for(Duration::from_secs(1));
+        let raft = sim.actor(&leader).unwrap();
+        assert!(raft.is_leader());
+        assert!(raft.snapshot_index > 0);
+        assert!(raft.log.len() as LogIndex <= SNAPSHOT_INTERVAL);
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(2));
+        let expected = sim.actor(&leader).unwrap().machine.snapshot();
+        assert_eq!(expected.len(), writes as usize);
+        assert!(sim.actor(&lagging).unwrap().snapshot_index > 0);
+        for node in &nodes {
+            assert_eq!(sim.actor(node).unwrap().machine.snapshot(), expected);
+        }
+    }
+}
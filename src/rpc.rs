@@ -0,0 +1,114 @@
+//! Request/response correlation for RPCs sent through the runtime
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    actor::ActorID,
+    errors::Error,
+    message::{Message, MessageID},
+};
+
+/// Default amount of time to wait for a reply before giving up on a request
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outcome of a request sent with [`Handle::call`](crate::runtime::Handle::call)
+/// (or `Context::call` in the async runtime)
+#[derive(Debug)]
+pub struct RpcReply {
+    /// msg_id that the runtime assigned to the original request
+    pub request_id: MessageID,
+    /// Node the request was sent to
+    pub dest: ActorID,
+    /// The reply, the error the peer answered with, or `Error::Timeout`
+    pub result: Result<Message<Value>, Error>,
+}
+
+impl RpcReply {
+    /// Parse the reply into a typed message.
+    /// Errors replied by the peer (and timeouts) are passed through as is.
+    pub fn decode<U: DeserializeOwned>(&self) -> Result<Message<U>, Error> {
+        let msg = self.result.as_ref().map_err(Clone::clone)?;
+        Message {
+            src: msg.src.to_owned(),
+            dest: msg.dest.to_owned(),
+            body: msg.body.clone(),
+        }
+        .decode()
+    }
+}
+
+/// Turn `error` bodies into the `Error` they carry
+pub(crate) fn reply_result(msg: Message<Value>) -> Result<Message<Value>, Error> {
+    match msg.message_type() {
+        Some("error") => Err(Error::from_code(
+            msg.body["code"].as_u64().unwrap_or(13),
+            msg.body["text"].as_str().unwrap_or_default().to_owned(),
+        )),
+        _ => Ok(msg),
+    }
+}
+
+struct Pending {
+    dest: ActorID,
+    deadline: Instant,
+}
+
+/// Requests that are still waiting for a reply, keyed by their msg_id
+#[derive(Default)]
+pub(crate) struct PendingRequests {
+    pending: HashMap<MessageID, Pending>,
+}
+
+impl PendingRequests {
+    pub fn insert(&mut self, msg_id: MessageID, dest: ActorID, deadline: Instant) {
+        self.pending.insert(msg_id, Pending { dest, deadline });
+    }
+
+    /// Match an incoming message against the outstanding requests.
+    /// Returns the completed reply if `msg` answers one of them.
+    pub fn complete(&mut self, msg: Message<Value>) -> Result<RpcReply, Message<Value>> {
+        let Some(request_id) = msg.in_reply_to() else {
+            return Err(msg);
+        };
+        let Some(pending) = self.pending.remove(&request_id) else {
+            return Err(msg);
+        };
+        Ok(RpcReply {
+            request_id,
+            dest: pending.dest,
+            result: reply_result(msg),
+        })
+    }
+
+    /// Earliest point in time at which a request times out
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Remove every request whose deadline has passed, answering them with `Error::Timeout`
+    pub fn expire(&mut self, now: Instant) -> Vec<RpcReply> {
+        let expired: Vec<MessageID> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut replies: Vec<RpcReply> = expired
+            .into_iter()
+            .filter_map(|id| {
+                self.pending.remove(&id).map(|p| RpcReply {
+                    request_id: id,
+                    dest: p.dest,
+                    result: Err(Error::Timeout),
+                })
+            })
+            .collect();
+        replies.sort_by_key(|r| r.request_id);
+        replies
+    }
+}
@@ -1,17 +1,441 @@
 use crate::{
-    actor::Actor,
-    message::{Message, MessageID},
+    actor::{Actor, ActorID},
+    clock::{unix_millis, HlcTimestamp, HybridLogicalClock},
+    errors::{Error, ErrorBody},
+    message::{Message, MessageID, MessageIdAllocator},
+    rpc::PendingRequests,
+    timer::{TimerId, Timers},
 };
+use rand::{rngs::StdRng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    sync::mpsc::{self, Receiver, Sender},
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Field of the body in which the clock is piggybacked on messages between nodes
+const CLOCK_FIELD: &str = "hlc";
+
+/// Everything the runtime loop reacts to
+pub(crate) enum Event<T> {
+    /// A message read from stdin
+    Received(Message<Value>),
+    /// A line read from stdin which is not a message at all
+    Unparseable(String),
+    /// A message the actor sent to itself
+    Injected(Message<T>),
+    /// A fire-and-forget message to write out
+    Send(Message<Value>),
+    /// A request whose reply should be routed back to the actor
+    Call {
+        message: Message<Value>,
+        msg_id: MessageID,
+        timeout: Duration,
+    },
+    /// A timer to start, repeating every `period` if set
+    Schedule {
+        id: TimerId,
+        delay: Duration,
+        period: Option<Duration>,
+    },
+    /// A timer to stop
+    Cancel(TimerId),
+}
+
+/// Wall-clock time of a node: the system clock, or the virtual time of the simulator
+#[derive(Clone, Debug, Default)]
+pub(crate) enum WallClock {
+    #[default]
+    System,
+    /// Milliseconds since the UNIX epoch, moved forward by the simulator
+    Virtual(Arc<AtomicU64>),
+}
+
+impl WallClock {
+    pub fn now(&self) -> SystemTime {
+        match self {
+            WallClock::System => SystemTime::now(),
+            WallClock::Virtual(millis) => {
+                UNIX_EPOCH + Duration::from_millis(millis.load(Ordering::Relaxed))
+            }
+        }
+    }
+}
+
+/// Handle given to the actor on init to talk back to the runtime
+pub struct Handle<T> {
+    node_id: ActorID,
+    msg_ids: MessageIdAllocator,
+    timer_ids: Arc<AtomicU64>,
+    clock: Arc<Mutex<HybridLogicalClock>>,
+    wall_clock: WallClock,
+    rng: Arc<Mutex<StdRng>>,
+    tx: Sender<Event<T>>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            node_id: self.node_id.to_owned(),
+            msg_ids: self.msg_ids.clone(),
+            timer_ids: self.timer_ids.clone(),
+            clock: self.clock.clone(),
+            wall_clock: self.wall_clock.clone(),
+            rng: self.rng.clone(),
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> Handle<T> {
+    /// A handle with a clock and a random generator of its own. [`Node::handle`] shares those of the node.
+    pub(crate) fn new(node_id: ActorID, tx: Sender<Event<T>>) -> Self {
+        Self {
+            node_id,
+            msg_ids: Default::default(),
+            timer_ids: Default::default(),
+            clock: Default::default(),
+            wall_clock: Default::default(),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            tx,
+        }
+    }
+}
+
+impl<T: Serialize> Handle<T> {
+    /// Name of the node we are running as
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Allocate a msg_id for a request we build ourselves
+    pub fn next_msg_id(&self) -> MessageID {
+        self.msg_ids.next()
+    }
+
+    /// Timestamp a local event with the [`HybridLogicalClock`] of the node. With
+    /// [`Runtime::with_piggybacked_clock`], it is also ordered after every message received from other nodes.
+    pub fn timestamp(&self) -> HlcTimestamp {
+        let now = unix_millis(self.now());
+        self.clock.lock().unwrap().now_at(now)
+    }
+
+    /// Wall-clock time. Actors should use it rather than `SystemTime::now`, so that the
+    /// simulator can run them on virtual time.
+    pub fn now(&self) -> SystemTime {
+        self.wall_clock.now()
+    }
+
+    /// Random generator of the node. Actors should draw from it rather than `thread_rng`,
+    /// so that the simulator can replay a run from its seed.
+    pub fn rng(&self) -> MutexGuard<'_, StdRng> {
+        self.rng.lock().unwrap()
+    }
+
+    /// Deliver a message to our own actor, as if it came from the network
+    pub fn inject(&self, msg: Message<T>) -> Result<(), Error> {
+        self.tx
+            .send(Event::Injected(msg))
+            .map_err(|_| Error::TemporarilyUnavailable)
+    }
+
+    /// Send a message without expecting a reply
+    pub fn send(&self, msg: Message<T>) -> Result<(), Error> {
+        self.tx
+            .send(Event::Send(msg.into_value()))
+            .map_err(|_| Error::TemporarilyUnavailable)
+    }
+
+    /// Send a request to `dest`. The runtime fills in a fresh `msg_id` and hands
+    /// the matching reply (or a timeout) to [`Actor::on_reply`].
+    /// Returns the msg_id assigned to the request.
+    pub fn call<U: Serialize>(
+        &self,
+        dest: ActorID,
+        body: &U,
+        timeout: Duration,
+    ) -> Result<MessageID, Error> {
+        let mut body = serde_json::to_value(body).map_err(|_| Error::MalformedRequest)?;
+        let msg_id = self.msg_ids.next();
+        match body.as_object_mut() {
+            Some(fields) => fields.insert("msg_id".to_owned(), msg_id.into()),
+            None => return Err(Error::MalformedRequest),
+        };
+        self.tx
+            .send(Event::Call {
+                message: Message {
+                    src: self.node_id.to_owned(),
+                    dest,
+                    body,
+                },
+                msg_id,
+                timeout,
+            })
+            .map_err(|_| Error::TemporarilyUnavailable)?;
+        Ok(msg_id)
+    }
+
+    /// Fire [`Actor::on_timer`] once, after `delay`
+    pub fn schedule_once(&self, delay: Duration) -> Result<TimerId, Error> {
+        self.schedule(delay, None)
+    }
+
+    /// Fire [`Actor::on_timer`] every `period`, until cancelled
+    pub fn schedule_every(&self, period: Duration) -> Result<TimerId, Error> {
+        self.schedule(period, Some(period))
+    }
+
+    /// Stop a timer. Cancelling a timer which already fired is a no-op.
+    pub fn cancel(&self, timer: TimerId) -> Result<(), Error> {
+        self.tx
+            .send(Event::Cancel(timer))
+            .map_err(|_| Error::TemporarilyUnavailable)
+    }
+
+    fn schedule(&self, delay: Duration, period: Option<Duration>) -> Result<TimerId, Error> {
+        let id = TimerId(self.timer_ids.fetch_add(1, Ordering::Relaxed));
+        self.tx
+            .send(Event::Schedule { id, delay, period })
+            .map_err(|_| Error::TemporarilyUnavailable)?;
+        Ok(id)
+    }
+}
+
+/// What the runtime does with messages it cannot parse
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MalformedPolicy {
+    /// Answer with `Error::NotSupported` for unknown message types and
+    /// `Error::MalformedRequest` otherwise. Messages without a msg_id are dropped.
+    #[default]
+    Reply,
+    /// Log the message to stderr and carry on
+    Drop,
+    /// Crash the node
+    Abort,
+}
+
+impl MalformedPolicy {
+    /// Log (or crash on) a message we could not parse.
+    /// Returns whether the sender should be answered with an error.
+    pub(crate) fn reject(self, raw: &str, e: &Error) -> bool {
+        match self {
+            MalformedPolicy::Reply | MalformedPolicy::Drop => {
+                eprintln!("could not parse message ({}): {}", e, raw);
+                self == MalformedPolicy::Reply
+            }
+            MalformedPolicy::Abort => panic!("could not parse message ({}): {}", e, raw),
+        }
+    }
+}
+
+/// An actor together with the timers and requests it has in flight.
+/// Shared by [`Runtime`] and the simulator, which only differ in how messages are carried.
+pub(crate) struct Node<T: Actor> {
+    pub actor: T,
+    pending: PendingRequests,
+    timers: Timers,
+    pub malformed_policy: MalformedPolicy,
+    /// Clock shared with the handle of the actor
+    pub clock: Arc<Mutex<HybridLogicalClock>>,
+    pub wall_clock: WallClock,
+    /// Random generator shared with the handle of the actor
+    pub rng: Arc<Mutex<StdRng>>,
+    /// Whether to piggyback the clock on messages to the nodes in `node_ids`
+    pub piggyback_clock: bool,
+    pub node_ids: HashSet<ActorID>,
+}
+
+impl<T: Actor> Node<T> {
+    pub fn new(actor: T) -> Self {
+        Self {
+            actor,
+            pending: Default::default(),
+            timers: Default::default(),
+            malformed_policy: Default::default(),
+            clock: Default::default(),
+            wall_clock: Default::default(),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            piggyback_clock: false,
+            node_ids: Default::default(),
+        }
+    }
+
+    /// Handle for the actor of the node, sharing its clocks and random generator
+    pub fn handle(
+        &self,
+        node_id: ActorID,
+        tx: Sender<Event<T::MessagePayload>>,
+    ) -> Handle<T::MessagePayload> {
+        Handle {
+            clock: self.clock.clone(),
+            wall_clock: self.wall_clock.clone(),
+            rng: self.rng.clone(),
+            ..Handle::new(node_id, tx)
+        }
+    }
+
+    /// Earliest point in time at which a timer fires or a request times out
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [self.pending.next_deadline(), self.timers.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// React to `event`, pushing the messages to send into `out`
+    pub fn handle_event(
+        &mut self,
+        event: Event<T::MessagePayload>,
+        now: Instant,
+        out: &mut Vec<Message<Value>>,
+    ) {
+        let start = out.len();
+        self.dispatch(event, now, out);
+        self.stamp_clock(&mut out[start..]);
+    }
+
+    fn dispatch(
+        &mut self,
+        event: Event<T::MessagePayload>,
+        now: Instant,
+        out: &mut Vec<Message<Value>>,
+    ) {
+        match event {
+            Event::Received(msg) => match self.pending.complete(self.observe_clock(msg)) {
+                Ok(reply) => {
+                    let result = self.actor.on_reply(reply);
+                    Self::emit(result, out);
+                }
+                Err(msg) => {
+                    let request_id = msg.msg_id();
+                    let raw = msg.body.to_string();
+                    let (src, dest) = (msg.src.to_owned(), msg.dest.to_owned());
+                    match msg.decode() {
+                        Ok(msg) => {
+                            let result = self.actor.receive(&msg);
+                            Self::emit_or_reply_error(&msg, request_id, result, out);
+                        }
+                        Err(e) => {
+                            if let (true, Some(request_id)) =
+                                (self.malformed_policy.reject(&raw, &e), request_id)
+                            {
+                                let reply = Message {
+                                    src: dest,
+                                    dest: src,
+                                    body: ErrorBody::new(&e, request_id),
+                                };
+                                out.push(reply.into_value());
+                            }
+                        }
+                    }
+                }
+            },
+            Event::Unparseable(line) => {
+                self.malformed_policy
+                    .reject(&line, &Error::MalformedRequest);
+            }
+            Event::Injected(msg) => {
+                let result = self.actor.receive(&msg);
+                Self::emit(result, out);
+            }
+            Event::Send(msg) => out.push(msg),
+            Event::Call {
+                message,
+                msg_id,
+                timeout,
+            } => {
+                self.pending
+                    .insert(msg_id, message.dest.to_owned(), now + timeout);
+                out.push(message);
+            }
+            Event::Schedule { id, delay, period } => self.timers.schedule(id, now + delay, period),
+            Event::Cancel(id) => self.timers.cancel(id),
+        }
+    }
+
+    /// Fire the timers which are due and time out the requests which have not been answered
+    pub fn fire_due(&mut self, now: Instant, out: &mut Vec<Message<Value>>) {
+        let start = out.len();
+        for timer in self.timers.fire(now) {
+            let result = self.actor.on_timer(timer);
+            Self::emit(result, out);
+        }
+        for reply in self.pending.expire(now) {
+            let result = self.actor.on_reply(reply);
+            Self::emit(result, out);
+        }
+        self.stamp_clock(&mut out[start..]);
+    }
+
+    /// Take the clock piggybacked on a message from another node into ours
+    fn observe_clock(&self, mut msg: Message<Value>) -> Message<Value> {
+        if self.piggyback_clock {
+            let remote = msg
+                .body
+                .as_object_mut()
+                .and_then(|fields| fields.remove(CLOCK_FIELD))
+                .and_then(|clock| serde_json::from_value(clock).ok());
+            if let Some(remote) = remote {
+                let now = unix_millis(self.wall_clock.now());
+                self.clock.lock().unwrap().receive_at(remote, now);
+            }
+        }
+        msg
+    }
+
+    /// Piggyback our clock on the messages to other nodes
+    fn stamp_clock(&self, msgs: &mut [Message<Value>]) {
+        if !self.piggyback_clock {
+            return;
+        }
+        for msg in msgs.iter_mut().filter(|m| self.node_ids.contains(&m.dest)) {
+            let now = unix_millis(self.wall_clock.now());
+            let timestamp = self.clock.lock().unwrap().now_at(now);
+            if let Some(fields) = msg.body.as_object_mut() {
+                let timestamp =
+                    serde_json::to_value(timestamp).expect("expected clock to marshall to json");
+                fields.insert(CLOCK_FIELD.to_owned(), timestamp);
+            }
+        }
+    }
+
+    fn emit(result: Result<Vec<Message<T::MessagePayload>>, Error>, out: &mut Vec<Message<Value>>) {
+        match result {
+            Ok(responses) => out.extend(responses.into_iter().map(Message::into_value)),
+            Err(e) => eprintln!("errored while handling message: {:?}", e),
+        }
+    }
+
+    /// Like `emit`, but answers a failed request with an `error` message
+    /// so that the requester gets a definite answer instead of a timeout
+    fn emit_or_reply_error(
+        request: &Message<T::MessagePayload>,
+        request_id: Option<MessageID>,
+        result: Result<Vec<Message<T::MessagePayload>>, Error>,
+        out: &mut Vec<Message<Value>>,
+    ) {
+        match (result, request_id) {
+            (Err(e), Some(request_id)) => {
+                eprintln!("errored while handling message: {:?}", e);
+                let reply = Message::new_reply_to(request, ErrorBody::new(&e, request_id));
+                out.push(reply.into_value());
+            }
+            (result, _) => Self::emit(result, out),
+        }
+    }
+}
+
 pub struct Runtime<T: Actor + Default + Send> {
-    node: T,
-    rx: Receiver<Message<T::MessagePayload>>,
-    pub tx: Sender<Message<T::MessagePayload>>,
+    node: Node<T>,
+    rx: Receiver<Event<T::MessagePayload>>,
+    tx: Sender<Event<T::MessagePayload>>,
 }
 
 impl<T: Actor + Default + Send + 'static> Default for Runtime<T> {
@@ -21,29 +445,42 @@ impl<T: Actor + Default + Send + 'static> Default for Runtime<T> {
 }
 
 #[derive(Deserialize)]
-struct InitMsg {
-    msg_id: MessageID,
-    node_id: String,
-    node_ids: Vec<String>,
+pub(crate) struct InitMsg {
+    pub msg_id: MessageID,
+    pub node_id: String,
+    pub node_ids: Vec<String>,
 }
 
 #[derive(Serialize)]
-struct InitAckMsg {
+pub(crate) struct InitAckMsg {
     #[serde(rename = "type")]
-    message_type: String,
-    in_reply_to: MessageID,
+    pub message_type: String,
+    pub in_reply_to: MessageID,
 }
 
 impl<T: Actor + Default + Send + 'static> Runtime<T> {
     pub fn new() -> Self {
-        let (tx, rx) = mpsc::channel::<Message<T::MessagePayload>>();
+        let (tx, rx) = mpsc::channel::<Event<T::MessagePayload>>();
         Self {
-            node: Default::default(),
+            node: Node::new(Default::default()),
             rx,
             tx,
         }
     }
 
+    /// Choose what happens to messages which cannot be parsed. Defaults to `MalformedPolicy::Reply`.
+    pub fn with_malformed_policy(mut self, policy: MalformedPolicy) -> Self {
+        self.node.malformed_policy = policy;
+        self
+    }
+
+    /// Piggyback the clock behind [`Handle::timestamp`] on every message sent to another node,
+    /// and take in the clock of every message received from one. Off by default.
+    pub fn with_piggybacked_clock(mut self) -> Self {
+        self.node.piggyback_clock = true;
+        self
+    }
+
     fn init(&mut self) {
         let mut buffer = String::new();
         // read an init message
@@ -52,10 +489,16 @@ impl<T: Actor + Default + Send + 'static> Runtime<T> {
             .expect("could not read stdin");
 
         // initialize node
-        let init_msg: Message<InitMsg> = Message::deserialize(&buffer);
+        let init_msg: Message<InitMsg> =
+            Message::deserialize(&buffer).expect("expected a valid init message");
+        self.node.node_ids = init_msg.body.node_ids.iter().cloned().collect();
+        let handle = self
+            .node
+            .handle(init_msg.body.node_id.to_owned(), self.tx.clone());
         self.node
+            .actor
             .init(
-                self.tx.clone(),
+                handle,
                 init_msg.body.node_id.to_owned(),
                 init_msg.body.node_ids.to_owned(),
             )
@@ -73,30 +516,51 @@ impl<T: Actor + Default + Send + 'static> Runtime<T> {
         self.init();
 
         let tx = self.tx.clone();
-        let jh = thread::spawn(move || loop {
+        let jh = thread::spawn(move || {
             for raw_line in std::io::stdin().lines() {
-                let line = raw_line.unwrap();
-                let msg: Message<T::MessagePayload> = Message::deserialize(&line);
-                tx.send(msg).expect("sending message through tx");
+                let line = match raw_line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        eprintln!("could not read stdin: {}", e);
+                        break;
+                    }
+                };
+                let event = match Message::deserialize(&line) {
+                    Ok(msg) => Event::Received(msg),
+                    Err(_) => Event::Unparseable(line),
+                };
+                tx.send(event).expect("sending message through tx");
             }
         });
 
-        for msg in self.rx.iter() {
-            match self.node.receive(&msg) {
-                Ok(responses) => {
-                    for resp in responses {
-                        println!("{}", resp.serialize());
+        let mut out = vec![];
+        loop {
+            let event = match self.node.next_deadline() {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    match self.rx.recv_timeout(wait) {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
                     }
                 }
-                Err(e) => {
-                    eprintln!("errored while handling message: {:?}", e);
-                    continue;
-                }
+                None => match self.rx.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => break,
+                },
+            };
+
+            if let Some(event) = event {
+                self.node.handle_event(event, Instant::now(), &mut out);
+            }
+            self.node.fire_due(Instant::now(), &mut out);
+            for msg in out.drain(..) {
+                println!("{}", msg.serialize());
             }
         }
 
         match jh.join() {
-            Ok(_) => {},
+            Ok(_) => {}
             Err(e) => eprintln!("panicked on joining thread: {:?}", e),
         }
     }
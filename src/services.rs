@@ -0,0 +1,537 @@
+//! Clients for the services built into Maelstrom.
+//! Taken from the [Services doc](https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md).
+//!
+//! With the blocking [`Runtime`](crate::runtime::Runtime), requests are sent through a
+//! [`Handle`] and their outcome comes back to `Actor::on_reply`, where it can be parsed with
+//! [`KvResponse::from_reply`] / [`TsoResponse::from_reply`]. With the `async` feature,
+//! `AsyncKvClient` and `AsyncTsoClient` await the typed result directly.
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    actor::ActorID,
+    errors::Error,
+    message::{Body, MessageID},
+    rpc::{self, RpcReply},
+    runtime::Handle,
+};
+
+/// A linearizable key-value store
+pub const LIN_KV: &str = "lin-kv";
+/// A sequentially consistent key-value store
+pub const SEQ_KV: &str = "seq-kv";
+/// A last-write-wins key-value store, which may lose writes and serve stale reads
+pub const LWW_KV: &str = "lww-kv";
+/// A linearizable timestamp oracle, handing out monotonically increasing timestamps
+pub const LIN_TSO: &str = "lin-tso";
+
+/// Requests understood by the key-value services. Keys and values can be any JSON value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvRequest {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    /// Set `key` to `to` if it currently holds `from`.
+    /// Fails with `Error::KeyDoesNotExist` unless `create_if_not_exists` is set.
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+/// Replies of the key-value services
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvResponse {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+}
+
+impl KvResponse {
+    /// Parse the outcome of a request to a key-value service.
+    /// Errors such as `KeyDoesNotExist` or `PreconditionFailed` are passed through.
+    pub fn from_reply(reply: &RpcReply) -> Result<Self, Error> {
+        reply.decode::<Body<KvResponse>>().map(|m| m.body.payload)
+    }
+}
+
+/// Requests understood by the timestamp oracle
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TsoRequest {
+    Ts,
+}
+
+/// Replies of the timestamp oracle
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TsoResponse {
+    TsOk { ts: u64 },
+}
+
+impl TsoResponse {
+    /// Parse the outcome of a request to the timestamp oracle into the timestamp
+    pub fn from_reply(reply: &RpcReply) -> Result<u64, Error> {
+        match reply.decode::<Body<TsoResponse>>()?.body.payload {
+            TsoResponse::TsOk { ts } => Ok(ts),
+        }
+    }
+}
+
+/// Client for one of the key-value services.
+/// Every method returns the msg_id of the request, to match it with the reply given to `Actor::on_reply`.
+pub struct KvClient<T> {
+    service: ActorID,
+    handle: Handle<T>,
+    timeout: Duration,
+}
+
+impl<T: Serialize> KvClient<T> {
+    pub fn new(service: &str, handle: Handle<T>) -> Self {
+        Self {
+            service: service.to_owned(),
+            handle,
+            timeout: rpc::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for the service before giving up with `Error::Timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn read(&self, key: impl Into<Value>) -> Result<MessageID, Error> {
+        self.request(KvRequest::Read { key: key.into() })
+    }
+
+    pub fn write(
+        &self,
+        key: impl Into<Value>,
+        value: impl Into<Value>,
+    ) -> Result<MessageID, Error> {
+        self.request(KvRequest::Write {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn cas(
+        &self,
+        key: impl Into<Value>,
+        from: impl Into<Value>,
+        to: impl Into<Value>,
+        create_if_not_exists: bool,
+    ) -> Result<MessageID, Error> {
+        self.request(KvRequest::Cas {
+            key: key.into(),
+            from: from.into(),
+            to: to.into(),
+            create_if_not_exists,
+        })
+    }
+
+    fn request(&self, request: KvRequest) -> Result<MessageID, Error> {
+        self.handle
+            .call(self.service.to_owned(), &request, self.timeout)
+    }
+}
+
+/// Client for the timestamp oracle.
+/// Returns the msg_id of the request, to match it with the reply given to `Actor::on_reply`.
+pub struct TsoClient<T> {
+    handle: Handle<T>,
+    timeout: Duration,
+}
+
+impl<T: Serialize> TsoClient<T> {
+    pub fn new(handle: Handle<T>) -> Self {
+        Self {
+            handle,
+            timeout: rpc::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for the service before giving up with `Error::Timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn ts(&self) -> Result<MessageID, Error> {
+        self.handle
+            .call(LIN_TSO.to_owned(), &TsoRequest::Ts, self.timeout)
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncKvClient, AsyncTsoClient};
+
+#[cfg(feature = "async")]
+mod asynchronous {
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::{KvRequest, KvResponse, TsoRequest, TsoResponse, LIN_TSO};
+    use crate::{actor::ActorID, async_runtime::Context, errors::Error, rpc};
+
+    /// Client for one of the key-value services, for the async runtime
+    #[derive(Clone)]
+    pub struct AsyncKvClient {
+        service: ActorID,
+        ctx: Context,
+        timeout: Duration,
+    }
+
+    impl AsyncKvClient {
+        pub fn new(service: &str, ctx: Context) -> Self {
+            Self {
+                service: service.to_owned(),
+                ctx,
+                timeout: rpc::DEFAULT_TIMEOUT,
+            }
+        }
+
+        /// How long to wait for the service before giving up with `Error::Timeout`
+        pub fn with_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        pub async fn read(&self, key: impl Into<Value>) -> Result<Value, Error> {
+            match self.request(KvRequest::Read { key: key.into() }).await? {
+                KvResponse::ReadOk { value } => Ok(value),
+                _ => Err(Error::MalformedRequest),
+            }
+        }
+
+        pub async fn write(
+            &self,
+            key: impl Into<Value>,
+            value: impl Into<Value>,
+        ) -> Result<(), Error> {
+            let request = KvRequest::Write {
+                key: key.into(),
+                value: value.into(),
+            };
+            match self.request(request).await? {
+                KvResponse::WriteOk => Ok(()),
+                _ => Err(Error::MalformedRequest),
+            }
+        }
+
+        pub async fn cas(
+            &self,
+            key: impl Into<Value>,
+            from: impl Into<Value>,
+            to: impl Into<Value>,
+            create_if_not_exists: bool,
+        ) -> Result<(), Error> {
+            let request = KvRequest::Cas {
+                key: key.into(),
+                from: from.into(),
+                to: to.into(),
+                create_if_not_exists,
+            };
+            match self.request(request).await? {
+                KvResponse::CasOk => Ok(()),
+                _ => Err(Error::MalformedRequest),
+            }
+        }
+
+        async fn request(&self, request: KvRequest) -> Result<KvResponse, Error> {
+            let reply = self
+                .ctx
+                .call_timeout(self.service.to_owned(), &request, self.timeout)
+                .await;
+            KvResponse::from_reply(&reply)
+        }
+    }
+
+    /// Client for the timestamp oracle, for the async runtime
+    #[derive(Clone)]
+    pub struct AsyncTsoClient {
+        ctx: Context,
+        timeout: Duration,
+    }
+
+    impl AsyncTsoClient {
+        pub fn new(ctx: Context) -> Self {
+            Self {
+                ctx,
+                timeout: rpc::DEFAULT_TIMEOUT,
+            }
+        }
+
+        /// How long to wait for the service before giving up with `Error::Timeout`
+        pub fn with_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        pub async fn ts(&self) -> Result<u64, Error> {
+            let reply = self
+                .ctx
+                .call_timeout(LIN_TSO.to_owned(), &TsoRequest::Ts, self.timeout)
+                .await;
+            TsoResponse::from_reply(&reply)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        actor::Actor,
+        message::Message,
+        sim::{NetworkConfig, Simulation},
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn reply(result: Result<Value, Error>) -> RpcReply {
+        RpcReply {
+            request_id: 1,
+            dest: LIN_KV.to_owned(),
+            result: result.map(|body| Message {
+                src: LIN_KV.to_owned(),
+                dest: "n0".to_owned(),
+                body,
+            }),
+        }
+    }
+
+    #[test]
+    fn requests_serialize_like_the_services_expect() {
+        let read = KvRequest::Read { key: json!(1) };
+        assert_eq!(json!(read), json!({"type": "read", "key": 1}));
+        let write = KvRequest::Write {
+            key: json!("k"),
+            value: json!([1, 2]),
+        };
+        assert_eq!(
+            json!(write),
+            json!({"type": "write", "key": "k", "value": [1, 2]})
+        );
+        let cas = |create_if_not_exists| KvRequest::Cas {
+            key: json!(1),
+            from: json!(2),
+            to: json!(3),
+            create_if_not_exists,
+        };
+        assert_eq!(
+            json!(cas(false)),
+            json!({"type": "cas", "key": 1, "from": 2, "to": 3})
+        );
+        assert_eq!(
+            json!(cas(true)),
+            json!({"type": "cas", "key": 1, "from": 2, "to": 3, "create_if_not_exists": true})
+        );
+        assert_eq!(json!(TsoRequest::Ts), json!({"type": "ts"}));
+    }
+
+    #[test]
+    fn replies_are_parsed_into_responses() {
+        let read_ok = reply(Ok(json!({"type": "read_ok", "in_reply_to": 1, "value": 4})));
+        assert!(matches!(
+            KvResponse::from_reply(&read_ok),
+            Ok(KvResponse::ReadOk { value }) if value == json!(4)
+        ));
+        let cas_ok = reply(Ok(json!({"type": "cas_ok", "in_reply_to": 1})));
+        assert!(matches!(
+            KvResponse::from_reply(&cas_ok),
+            Ok(KvResponse::CasOk)
+        ));
+        let ts_ok = reply(Ok(json!({"type": "ts_ok", "in_reply_to": 1, "ts": 7})));
+        assert_eq!(TsoResponse::from_reply(&ts_ok).unwrap(), 7);
+    }
+
+    #[test]
+    fn errors_are_passed_through_typed() {
+        let missing = reply(Err(Error::KeyDoesNotExist));
+        assert!(matches!(
+            KvResponse::from_reply(&missing),
+            Err(Error::KeyDoesNotExist)
+        ));
+        let failed = reply(Err(Error::PreconditionFailed));
+        assert!(matches!(
+            KvResponse::from_reply(&failed),
+            Err(Error::PreconditionFailed)
+        ));
+        let timeout = reply(Err(Error::Timeout));
+        assert!(matches!(
+            TsoResponse::from_reply(&timeout),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(untagged)]
+    enum Kv {
+        Request(KvRequest),
+        Response(KvResponse),
+    }
+
+    /// n1 plays a linearizable key-value service, n0 forwards the requests of clients to it
+    /// through a [`KvClient`]
+    #[derive(Default)]
+    struct KvNode {
+        client: Option<KvClient<Body<Kv>>>,
+        store: HashMap<String, Value>,
+        /// Outcome of every request forwarded, by the msg_id of the client request
+        outcomes: HashMap<MessageID, Result<KvResponse, Error>>,
+        /// msg_id of the client request behind each request to n1
+        forwarded: HashMap<MessageID, MessageID>,
+    }
+
+    impl KvNode {
+        fn serve(&mut self, request: &KvRequest) -> Result<KvResponse, Error> {
+            match request {
+                KvRequest::Read { key } => match self.store.get(&key.to_string()) {
+                    Some(value) => Ok(KvResponse::ReadOk {
+                        value: value.to_owned(),
+                    }),
+                    None => Err(Error::KeyDoesNotExist),
+                },
+                KvRequest::Write { key, value } => {
+                    self.store.insert(key.to_string(), value.to_owned());
+                    Ok(KvResponse::WriteOk)
+                }
+                KvRequest::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => {
+                    match self.store.get(&key.to_string()) {
+                        None if !create_if_not_exists => return Err(Error::KeyDoesNotExist),
+                        Some(value) if value != from => return Err(Error::PreconditionFailed),
+                        _ => {}
+                    }
+                    self.store.insert(key.to_string(), to.to_owned());
+                    Ok(KvResponse::CasOk)
+                }
+            }
+        }
+    }
+
+    impl Actor for KvNode {
+        type MessagePayload = Body<Kv>;
+
+        fn init(
+            &mut self,
+            handle: Handle<Self::MessagePayload>,
+            node_id: String,
+            _peers: Vec<String>,
+        ) -> Result<(), Error> {
+            if node_id == "n0" {
+                self.client = Some(KvClient::new("n1", handle));
+            }
+            Ok(())
+        }
+
+        fn receive(
+            &mut self,
+            message: &Message<Self::MessagePayload>,
+        ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+            let Kv::Request(request) = &message.body.payload else {
+                return Ok(vec![]);
+            };
+            let Some(client) = &self.client else {
+                let response = self.serve(request)?;
+                return Ok(vec![message.reply(Kv::Response(response))]);
+            };
+            let request_id = match request.to_owned() {
+                KvRequest::Read { key } => client.read(key)?,
+                KvRequest::Write { key, value } => client.write(key, value)?,
+                KvRequest::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => client.cas(key, from, to, create_if_not_exists)?,
+            };
+            let msg_id = message.body.msg_id.ok_or(Error::MalformedRequest)?;
+            self.forwarded.insert(request_id, msg_id);
+            Ok(vec![])
+        }
+
+        fn on_reply(
+            &mut self,
+            reply: RpcReply,
+        ) -> Result<Vec<Message<Self::MessagePayload>>, Error> {
+            let msg_id = self
+                .forwarded
+                .remove(&reply.request_id)
+                .ok_or(Error::Crash)?;
+            self.outcomes.insert(msg_id, KvResponse::from_reply(&reply));
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn kv_client_round_trip() {
+        let mut sim = Simulation::<KvNode>::new(2, 0, NetworkConfig::default()).unwrap();
+        let requests = [
+            KvRequest::Read { key: json!(1) },
+            KvRequest::Cas {
+                key: json!(1),
+                from: json!(0),
+                to: json!(1),
+                create_if_not_exists: false,
+            },
+            KvRequest::Cas {
+                key: json!(1),
+                from: json!(0),
+                to: json!(1),
+                create_if_not_exists: true,
+            },
+            KvRequest::Cas {
+                key: json!(1),
+                from: json!(0),
+                to: json!(2),
+                create_if_not_exists: false,
+            },
+            KvRequest::Write {
+                key: json!(1),
+                value: json!(3),
+            },
+            KvRequest::Read { key: json!(1) },
+        ];
+        let msg_ids: Vec<MessageID> = requests
+            .into_iter()
+            .map(|request| {
+                let msg_id = sim.request("c1", "n0", &Body::new(Kv::Request(request)));
+                sim.run_for(Duration::from_millis(20));
+                msg_id
+            })
+            .collect();
+
+        let outcomes = &sim.actor("n0").unwrap().outcomes;
+        let outcomes: Vec<&Result<KvResponse, Error>> =
+            msg_ids.iter().map(|id| &outcomes[id]).collect();
+        assert!(matches!(outcomes[0], Err(Error::KeyDoesNotExist)));
+        assert!(matches!(outcomes[1], Err(Error::KeyDoesNotExist)));
+        assert!(matches!(outcomes[2], Ok(KvResponse::CasOk)));
+        assert!(matches!(outcomes[3], Err(Error::PreconditionFailed)));
+        assert!(matches!(outcomes[4], Ok(KvResponse::WriteOk)));
+        assert!(matches!(outcomes[5], Ok(KvResponse::ReadOk { value }) if *value == json!(3)));
+    }
+}
@@ -0,0 +1,378 @@
+//! Deterministic, in-process network simulator.
+//!
+//! Runs a cluster of actors in a single process, on virtual time, so they can be exercised
+//! by `cargo test` without Maelstrom. Every random choice (latency, drops, duplicates) comes
+//! from a seeded RNG, so a run can be reproduced from its seed. Actors get an RNG seeded from
+//! it, and a wall-clock time following virtual time, through their
+//! [`Handle`](crate::runtime::Handle): runs are reproducible as long as actors use nothing else.
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    actor::{Actor, ActorID},
+    errors::Error,
+    message::{Message, MessageID, MessageIdAllocator},
+    runtime::{Event, Node, WallClock},
+};
+
+/// Wall-clock time at which simulations start, in milliseconds since the UNIX epoch
+/// (2023-11-14T22:13:20Z), so that runs do not depend on the day they run on
+const VIRTUAL_EPOCH_MS: u64 = 1_700_000_000_000;
+
+/// How the simulated network treats messages between nodes.
+/// Messages to and from clients are delayed, but never dropped, duplicated or partitioned away.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Shortest one-way latency
+    pub min_latency: Duration,
+    /// Longest one-way latency. Latencies are drawn uniformly, so messages get reordered.
+    pub max_latency: Duration,
+    /// Probability for a message to be lost
+    pub drop_rate: f64,
+    /// Probability for a message to be delivered twice
+    pub duplicate_rate: f64,
+}
+
+impl NetworkConfig {
+    /// Fails with `Error::MalformedRequest` if a rate is not a probability, or if the shortest
+    /// latency is above the longest
+    pub fn validate(&self) -> Result<(), Error> {
+        let probability = 0.0..=1.0;
+        if !probability.contains(&self.drop_rate)
+            || !probability.contains(&self.duplicate_rate)
+            || self.min_latency > self.max_latency
+        {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(())
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            min_latency: Duration::ZERO,
+            max_latency: Duration::from_millis(5),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+        }
+    }
+}
+
+struct SimNode<T: Actor> {
+    node: Node<T>,
+    rx: Receiver<Event<T::MessagePayload>>,
+}
+
+/// A cluster of `T` actors, named `n0`, `n1`, ..., talking over a simulated network
+pub struct Simulation<T: Actor + Default> {
+    rng: StdRng,
+    epoch: Instant,
+    now: Instant,
+    /// Virtual wall-clock time of every node, in milliseconds since the UNIX epoch
+    wall_clock: Arc<AtomicU64>,
+    config: NetworkConfig,
+    nodes: BTreeMap<ActorID, SimNode<T>>,
+    /// Messages on the wire, ordered by delivery time then by send order
+    in_flight: BTreeMap<(Instant, u64), Message<Value>>,
+    sent: u64,
+    /// Links which are cut, in both directions
+    partitions: HashSet<(ActorID, ActorID)>,
+    client_msg_ids: MessageIdAllocator,
+    replies: Vec<Message<Value>>,
+}
+
+impl<T: Actor + Default> Simulation<T> {
+    /// Initialize `node_count` nodes on a network behaving as `config`, drawing every random
+    /// choice from `seed`. Fails if `config` is not valid, see [`NetworkConfig::validate`].
+    pub fn new(node_count: usize, seed: u64, config: NetworkConfig) -> Result<Self, Error> {
+        config.validate()?;
+        let epoch = Instant::now();
+        let node_ids: Vec<ActorID> = (0..node_count).map(|i| format!("n{}", i)).collect();
+        let mut sim = Self {
+            rng: StdRng::seed_from_u64(seed),
+            epoch,
+            now: epoch,
+            wall_clock: Arc::new(AtomicU64::new(VIRTUAL_EPOCH_MS)),
+            config,
+            nodes: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            partitions: HashSet::new(),
+            client_msg_ids: Default::default(),
+            replies: vec![],
+        };
+
+        for node_id in &node_ids {
+            let (tx, rx) = mpsc::channel();
+            let mut node = Node::new(T::default());
+            node.node_ids = node_ids.iter().cloned().collect();
+            node.wall_clock = WallClock::Virtual(sim.wall_clock.clone());
+            node.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(sim.rng.gen())));
+            let handle = node.handle(node_id.to_owned(), tx);
+            node.actor
+                .init(handle, node_id.to_owned(), node_ids.to_owned())
+                .expect("initialization to not error");
+            sim.nodes.insert(node_id.to_owned(), SimNode { node, rx });
+        }
+        sim.flush();
+        Ok(sim)
+    }
+
+    /// Piggyback the clock of every node on the messages between nodes, like
+    /// [`Runtime::with_piggybacked_clock`](crate::runtime::Runtime::with_piggybacked_clock)
+    pub fn with_piggybacked_clock(mut self) -> Self {
+        for sim_node in self.nodes.values_mut() {
+            sim_node.node.piggyback_clock = true;
+        }
+        self
+    }
+
+    /// Change how the network behaves from now on. Fails if `config` is not valid.
+    pub fn set_network(&mut self, config: NetworkConfig) -> Result<(), Error> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Names of every node in the cluster
+    pub fn node_ids(&self) -> Vec<ActorID> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    /// Inspect the state of a node
+    pub fn actor(&self, node_id: &str) -> Option<&T> {
+        self.nodes.get(node_id).map(|n| &n.node.actor)
+    }
+
+    /// Virtual time elapsed since the cluster started
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.epoch
+    }
+
+    /// Send a request from `client` to `dest`, filling in a fresh msg_id which is returned.
+    /// The reply can be found with [`Simulation::reply_to`] once it has been delivered.
+    pub fn request<U: Serialize>(&mut self, client: &str, dest: &str, body: &U) -> MessageID {
+        let msg_id = self.client_msg_ids.next();
+        let mut body = serde_json::to_value(body).expect("expected request to marshall to json");
+        body.as_object_mut()
+            .expect("expected request to be a JSON object")
+            .insert("msg_id".to_owned(), msg_id.into());
+        self.transmit(Message {
+            src: client.to_owned(),
+            dest: dest.to_owned(),
+            body,
+        });
+        msg_id
+    }
+
+    /// Reply delivered to `client` for request `msg_id`, if any
+    pub fn reply_to(&self, client: &str, msg_id: MessageID) -> Option<&Message<Value>> {
+        self.replies
+            .iter()
+            .find(|m| m.dest == client && m.in_reply_to() == Some(msg_id))
+    }
+
+    /// Every message delivered to clients so far, in delivery order
+    pub fn replies(&self) -> &[Message<Value>] {
+        &self.replies
+    }
+
+    /// Cut every link between nodes of different groups. Nodes left out of every group are isolated.
+    pub fn partition(&mut self, groups: &[Vec<ActorID>]) {
+        let group_of = |node: &ActorID| groups.iter().position(|g| g.contains(node));
+        self.partitions.clear();
+        for a in self.nodes.keys() {
+            for b in self.nodes.keys() {
+                if a != b && (group_of(a).is_none() || group_of(a) != group_of(b)) {
+                    self.partitions.insert((a.to_owned(), b.to_owned()));
+                }
+            }
+        }
+    }
+
+    /// Restore every link between nodes
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+    }
+
+    /// Deliver messages and fire timers until `duration` of virtual time has elapsed
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        loop {
+            let next_delivery = self.in_flight.keys().next().map(|(at, _)| *at);
+            let next_deadline = self
+                .nodes
+                .values()
+                .filter_map(|n| n.node.next_deadline())
+                .min();
+            let next = match [next_delivery, next_deadline].into_iter().flatten().min() {
+                Some(next) if next <= end => next,
+                _ => break,
+            };
+            self.advance(next);
+
+            if next_delivery == Some(next) {
+                let (_, msg) = self
+                    .in_flight
+                    .pop_first()
+                    .expect("expected a message in flight");
+                self.deliver(msg);
+            } else {
+                let mut out = vec![];
+                for sim_node in self.nodes.values_mut() {
+                    sim_node.node.fire_due(self.now, &mut out);
+                }
+                out.into_iter().for_each(|msg| self.transmit(msg));
+            }
+            self.flush();
+        }
+        self.advance(end);
+    }
+
+    /// Move virtual time forward to `to`
+    fn advance(&mut self, to: Instant) {
+        self.now = self.now.max(to);
+        let elapsed = (self.now - self.epoch).as_millis() as u64;
+        self.wall_clock
+            .store(VIRTUAL_EPOCH_MS + elapsed, Ordering::Relaxed);
+    }
+
+    fn deliver(&mut self, msg: Message<Value>) {
+        match self.nodes.get_mut(&msg.dest) {
+            Some(sim_node) => {
+                let mut out = vec![];
+                sim_node
+                    .node
+                    .handle_event(Event::Received(msg), self.now, &mut out);
+                out.into_iter().for_each(|msg| self.transmit(msg));
+            }
+            None => self.replies.push(msg),
+        }
+    }
+
+    /// Process what the actors asked of their handles, until nothing is left
+    fn flush(&mut self) {
+        loop {
+            let mut out = vec![];
+            for sim_node in self.nodes.values_mut() {
+                while let Ok(event) = sim_node.rx.try_recv() {
+                    sim_node.node.handle_event(event, self.now, &mut out);
+                }
+            }
+            if out.is_empty() {
+                break;
+            }
+            out.into_iter().for_each(|msg| self.transmit(msg));
+        }
+    }
+
+    /// Put a message on the wire, subject to partitions, drops, duplication and latency
+    fn transmit(&mut self, msg: Message<Value>) {
+        let between_nodes = self.nodes.contains_key(&msg.src) && self.nodes.contains_key(&msg.dest);
+        let copies = if !between_nodes {
+            1
+        } else if self
+            .partitions
+            .contains(&(msg.src.to_owned(), msg.dest.to_owned()))
+            || self.rng.gen_bool(self.config.drop_rate)
+        {
+            0
+        } else if self.rng.gen_bool(self.config.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let latency = self
+                .rng
+                .gen_range(self.config.min_latency..=self.config.max_latency);
+            self.sent += 1;
+            self.in_flight
+                .insert((self.now + latency, self.sent), msg.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crdt::{DeltaCrdtActor, GCounter, Payload},
+        message::Body,
+    };
+    use serde_json::json;
+
+    type Counter = DeltaCrdtActor<GCounter>;
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        let invalid = [
+            NetworkConfig {
+                drop_rate: 1.5,
+                ..Default::default()
+            },
+            NetworkConfig {
+                duplicate_rate: -0.1,
+                ..Default::default()
+            },
+            NetworkConfig {
+                drop_rate: f64::NAN,
+                ..Default::default()
+            },
+            NetworkConfig {
+                min_latency: Duration::from_millis(10),
+                max_latency: Duration::from_millis(1),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(Simulation::<Counter>::new(3, 0, config.to_owned()).is_err());
+            let mut sim = Simulation::<Counter>::new(3, 0, Default::default()).unwrap();
+            assert!(sim.set_network(config).is_err());
+        }
+    }
+
+    #[test]
+    fn extreme_networks_are_accepted() {
+        let config = NetworkConfig {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(10),
+            drop_rate: 1.0,
+            duplicate_rate: 0.0,
+        };
+        let mut sim = Simulation::<Counter>::new(3, 0, config).unwrap();
+        let nodes = sim.node_ids();
+        for (i, node) in nodes.iter().enumerate() {
+            let delta = i as u64 + 1;
+            sim.request("c1", node, &Body::new(Payload::Add { delta }));
+        }
+        // long enough for many rounds of gossip, none of which gets through
+        sim.run_for(Duration::from_secs(1));
+
+        let reads: Vec<MessageID> = nodes
+            .iter()
+            .map(|node| sim.request("c1", node, &Body::new(Payload::<u64>::Read)))
+            .collect();
+        sim.run_for(Duration::from_millis(100));
+        // clients still get their replies, but every node only counts what it was sent
+        for (i, msg_id) in reads.into_iter().enumerate() {
+            let reply = sim.reply_to("c1", msg_id).unwrap();
+            assert_eq!(reply.body["type"], "read_ok");
+            assert_eq!(reply.body["value"], json!(i + 1), "{}", nodes[i]);
+        }
+        assert_eq!(sim.replies().len(), 2 * nodes.len());
+    }
+}
@@ -0,0 +1,183 @@
+//! Deterministic state machines, to be replicated by the consensus and replication modules.
+//!
+//! A state machine only turns commands into outputs: it knows nothing of messages or nodes.
+//! Replicas which apply the same commands in the same order end up in the same state, and
+//! a replica can be brought up to date from the snapshot of another one instead of its commands.
+use crate::{
+    errors::Error,
+    services::{KvRequest, KvResponse},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Application replicated by [`Raft`](crate::raft::Raft) or
+/// [`MultiPaxos`](crate::paxos::MultiPaxos)
+pub trait StateMachine: Default {
+    type Command: Serialize + DeserializeOwned + Clone + Send;
+    type Output: Serialize + DeserializeOwned + Clone + Send;
+    type Snapshot: Serialize + DeserializeOwned + Clone + Send;
+
+    /// Apply a command. It must only depend on the current state, so that every replica gets the
+    /// same output. Errors are part of the output: they are sent back to the client as is.
+    fn apply(&mut self, command: &Self::Command) -> Result<Self::Output, Error>;
+
+    /// Capture the current state
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replace the current state with one captured by [`StateMachine::snapshot`]
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// Key-value store with the semantics of Maelstrom's own key-value services
+#[derive(Default, Debug, Clone)]
+pub struct KvStateMachine {
+    /// Values by key. Keys can be any JSON value, so they are held in their JSON form.
+    values: HashMap<String, Value>,
+}
+
+impl KvStateMachine {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.values.get(&key.to_string())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl StateMachine for KvStateMachine {
+    type Command = KvRequest;
+    type Output = KvResponse;
+    /// Values by key, keys in their JSON form
+    type Snapshot = HashMap<String, Value>;
+
+    fn apply(&mut self, command: &KvRequest) -> Result<KvResponse, Error> {
+        match command {
+            KvRequest::Read { key } => self
+                .get(key)
+                .map(|value| KvResponse::ReadOk {
+                    value: value.to_owned(),
+                })
+                .ok_or(Error::KeyDoesNotExist),
+            KvRequest::Write { key, value } => {
+                self.values.insert(key.to_string(), value.to_owned());
+                Ok(KvResponse::WriteOk)
+            }
+            KvRequest::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.values.get_mut(&key.to_string()) {
+                Some(value) if value == from => {
+                    *value = to.to_owned();
+                    Ok(KvResponse::CasOk)
+                }
+                Some(_) => Err(Error::PreconditionFailed),
+                None if *create_if_not_exists => {
+                    self.values.insert(key.to_string(), to.to_owned());
+                    Ok(KvResponse::CasOk)
+                }
+                None => Err(Error::KeyDoesNotExist),
+            },
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.values.to_owned()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        self.values = snapshot;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write(key: Value, value: Value) -> KvRequest {
+        KvRequest::Write { key, value }
+    }
+
+    fn cas(key: Value, from: Value, to: Value, create_if_not_exists: bool) -> KvRequest {
+        KvRequest::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        }
+    }
+
+    fn read(machine: &mut KvStateMachine, key: Value) -> Result<Value, Error> {
+        match machine.apply(&KvRequest::Read { key })? {
+            KvResponse::ReadOk { value } => Ok(value),
+            response => panic!("expected read_ok, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn missing_keys_do_not_exist() {
+        let mut machine = KvStateMachine::default();
+        assert!(matches!(
+            read(&mut machine, json!(1)),
+            Err(Error::KeyDoesNotExist)
+        ));
+    }
+
+    #[test]
+    fn reads_see_the_last_write() {
+        let mut machine = KvStateMachine::default();
+        let response = machine.apply(&write(json!(1), json!("a"))).unwrap();
+        assert!(matches!(response, KvResponse::WriteOk));
+        machine.apply(&write(json!(1), json!("b"))).unwrap();
+        assert_eq!(read(&mut machine, json!(1)).unwrap(), json!("b"));
+        // keys are told apart by their JSON form
+        assert!(read(&mut machine, json!("1")).is_err());
+    }
+
+    #[test]
+    fn cas_only_replaces_the_expected_value() {
+        let mut machine = KvStateMachine::default();
+        machine.apply(&write(json!(1), json!(2))).unwrap();
+        let e = machine.apply(&cas(json!(1), json!(3), json!(4), false));
+        assert!(matches!(e, Err(Error::PreconditionFailed)));
+        assert_eq!(read(&mut machine, json!(1)).unwrap(), json!(2));
+
+        let response = machine.apply(&cas(json!(1), json!(2), json!(4), false));
+        assert!(matches!(response, Ok(KvResponse::CasOk)));
+        assert_eq!(read(&mut machine, json!(1)).unwrap(), json!(4));
+    }
+
+    #[test]
+    fn cas_creates_missing_keys_only_when_asked() {
+        let mut machine = KvStateMachine::default();
+        let e = machine.apply(&cas(json!(1), json!(2), json!(3), false));
+        assert!(matches!(e, Err(Error::KeyDoesNotExist)));
+        assert!(machine.is_empty());
+
+        let response = machine.apply(&cas(json!(1), json!(2), json!(3), true));
+        assert!(matches!(response, Ok(KvResponse::CasOk)));
+        assert_eq!(read(&mut machine, json!(1)).unwrap(), json!(3));
+    }
+
+    #[test]
+    fn restored_snapshots_hold_the_same_values() {
+        let mut machine = KvStateMachine::default();
+        machine.apply(&write(json!(1), json!("a"))).unwrap();
+        machine.apply(&write(json!([2]), json!("b"))).unwrap();
+        let mut restored = KvStateMachine::default();
+        restored.apply(&write(json!(3), json!("c"))).unwrap();
+        restored.restore(machine.snapshot());
+        assert_eq!(restored.len(), 2);
+        assert_eq!(read(&mut restored, json!(1)).unwrap(), json!("a"));
+        assert_eq!(read(&mut restored, json!([2])).unwrap(), json!("b"));
+        assert!(read(&mut restored, json!(3)).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! One-shot and periodic timers driven by the runtime
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Identifies a timer scheduled through the runtime. Handed to `Actor::on_timer` when it fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(pub(crate) u64);
+
+/// Timers waiting to fire, ordered by deadline
+#[derive(Default)]
+pub(crate) struct Timers {
+    queue: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    /// Timers which have not been cancelled, with their period if they repeat
+    active: HashMap<TimerId, Option<Duration>>,
+}
+
+impl Timers {
+    pub fn schedule(&mut self, id: TimerId, deadline: Instant, period: Option<Duration>) {
+        self.active.insert(id, period);
+        self.queue.push(Reverse((deadline, id)));
+    }
+
+    /// Cancelled timers are forgotten right away, and skipped once their deadline comes up
+    pub fn cancel(&mut self, id: TimerId) {
+        self.active.remove(&id);
+    }
+
+    /// Earliest point in time at which a timer may fire
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// Pop every timer due at `now`, rescheduling the periodic ones
+    pub fn fire(&mut self, now: Instant) -> Vec<TimerId> {
+        let mut fired = vec![];
+        while let Some(Reverse((deadline, id))) = self.queue.peek().copied() {
+            if deadline > now {
+                break;
+            }
+            self.queue.pop();
+            match self.active.get(&id).copied() {
+                Some(Some(period)) => {
+                    // skip the beats we missed rather than firing in a burst
+                    let next = match deadline + period {
+                        next if next > now => next,
+                        _ => now + period,
+                    };
+                    self.queue.push(Reverse((next, id)));
+                    fired.push(id);
+                }
+                Some(None) => {
+                    self.active.remove(&id);
+                    fired.push(id);
+                }
+                None => {}
+            }
+        }
+        fired
+    }
+}
@@ -0,0 +1,314 @@
+//! Overlays to broadcast over, computed from the full list of nodes.
+//!
+//! Every node computes the same overlay from the `node_ids` of `init`, without exchanging any
+//! message. Overlays are undirected: if `a` is a neighbour of `b`, `b` is a neighbour of `a`.
+//! Nodes are sorted first, so the order Maelstrom lists them in does not matter.
+use crate::{actor::ActorID, errors::Error};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+};
+
+/// Neighbours of every node, in the shape of the `topology` message of Maelstrom
+pub type Topology = HashMap<ActorID, Vec<ActorID>>;
+
+/// Seed of the random expander, shared by every node so that they compute the same one
+const EXPANDER_SEED: u64 = 0x6d61_656c_7374_726f;
+
+/// Shape of an overlay. Parsed from `line`, `ring`, `grid`, `tree:<k>`, `expander:<degree>`
+/// or `stars:<hubs>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlay {
+    /// Each node linked to the previous and the next one. Diameter n - 1.
+    Line,
+    /// A line whose ends are linked
+    Ring,
+    /// Nodes laid out row by row on a square, linked to their horizontal and vertical neighbours
+    Grid,
+    /// Tree where every node has up to `k` children. Diameter about 2 log_k(n).
+    Tree(usize),
+    /// Union of `degree / 2` random cycles through every node, so each node has up to `degree`
+    /// neighbours. Stays connected when a few links are cut.
+    Expander(usize),
+    /// Every node linked to one of `hubs` hubs, which are all linked to each other. Diameter 3.
+    Stars(usize),
+}
+
+impl Overlay {
+    /// Neighbours of every node of `node_ids` in this overlay
+    pub fn build(&self, node_ids: &[ActorID]) -> Topology {
+        let mut nodes = node_ids.to_vec();
+        nodes.sort();
+        nodes.dedup();
+        let n = nodes.len();
+        let mut edges: Vec<(usize, usize)> = vec![];
+        match *self {
+            Overlay::Line => edges.extend((1..n).map(|i| (i - 1, i))),
+            Overlay::Ring => {
+                edges.extend((1..n).map(|i| (i - 1, i)));
+                if n > 2 {
+                    edges.push((n - 1, 0));
+                }
+            }
+            Overlay::Grid => {
+                let width = (1..=n).find(|w| w * w >= n).unwrap_or(1);
+                for i in 0..n {
+                    if i % width + 1 < width && i + 1 < n {
+                        edges.push((i, i + 1));
+                    }
+                    if i + width < n {
+                        edges.push((i, i + width));
+                    }
+                }
+            }
+            Overlay::Tree(k) => edges.extend((1..n).map(|i| ((i - 1) / k.max(1), i))),
+            Overlay::Expander(degree) => {
+                let mut rng = StdRng::seed_from_u64(EXPANDER_SEED);
+                let mut order: Vec<usize> = (0..n).collect();
+                for _ in 0..(degree / 2).max(1) {
+                    order.shuffle(&mut rng);
+                    edges.extend(order.windows(2).map(|w| (w[0], w[1])));
+                    if n > 2 {
+                        edges.push((order[n - 1], order[0]));
+                    }
+                }
+            }
+            Overlay::Stars(hubs) => {
+                let hubs = hubs.clamp(1, n.max(1));
+                for i in 0..hubs {
+                    edges.extend((i + 1..hubs).map(|j| (i, j)));
+                }
+                edges.extend((hubs..n).map(|i| (i % hubs, i)));
+            }
+        }
+
+        let mut neighbours: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); n];
+        for (a, b) in edges {
+            if a != b {
+                neighbours[a].insert(b);
+                neighbours[b].insert(a);
+            }
+        }
+        nodes
+            .iter()
+            .zip(neighbours)
+            .map(|(node, links)| {
+                let links = links.into_iter().map(|i| nodes[i].to_owned()).collect();
+                (node.to_owned(), links)
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Overlay {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (shape, param) = match s.split_once(':') {
+            Some((shape, param)) => {
+                let param = param.parse().map_err(|_| Error::MalformedRequest)?;
+                (shape, Some(param))
+            }
+            None => (s, None),
+        };
+        match (shape, param) {
+            ("line", None) => Ok(Overlay::Line),
+            ("ring", None) => Ok(Overlay::Ring),
+            ("grid", None) => Ok(Overlay::Grid),
+            ("tree", Some(k)) if k > 0 => Ok(Overlay::Tree(k)),
+            ("expander", Some(degree)) if degree > 1 => Ok(Overlay::Expander(degree)),
+            ("stars", Some(hubs)) if hubs > 0 => Ok(Overlay::Stars(hubs)),
+            _ => Err(Error::MalformedRequest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const OVERLAYS: [Overlay; 8] = [
+        Overlay::Line,
+        Overlay::Ring,
+        Overlay::Grid,
+        Overlay::Tree(1),
+        Overlay::Tree(3),
+        Overlay::Expander(4),
+        Overlay::Stars(1),
+        Overlay::Stars(3),
+    ];
+
+    /// `n` nodes, named so that they sort in the order of their index
+    fn node_ids(n: usize) -> Vec<ActorID> {
+        (0..n).map(|i| format!("n{:02}", i)).collect()
+    }
+
+    fn degrees(topology: &Topology, nodes: &[ActorID]) -> Vec<usize> {
+        nodes.iter().map(|node| topology[node].len()).collect()
+    }
+
+    /// Longest of the shortest paths from `start` to every node it reaches, and how many it does
+    fn eccentricity(topology: &Topology, start: &ActorID) -> (usize, usize) {
+        let mut distances = HashMap::from([(start.to_owned(), 0)]);
+        let mut queue = VecDeque::from([start.to_owned()]);
+        while let Some(node) = queue.pop_front() {
+            let distance = distances[&node];
+            for peer in &topology[&node] {
+                if !distances.contains_key(peer) {
+                    distances.insert(peer.to_owned(), distance + 1);
+                    queue.push_back(peer.to_owned());
+                }
+            }
+        }
+        let farthest = distances.values().copied().max().unwrap_or_default();
+        (farthest, distances.len())
+    }
+
+    #[test]
+    fn overlays_are_symmetric_and_connected() {
+        for overlay in OVERLAYS {
+            for n in [1, 2, 3, 5, 16, 25] {
+                let nodes = node_ids(n);
+                let topology = overlay.build(&nodes);
+                assert_eq!(topology.len(), n, "{:?}", overlay);
+                for (node, peers) in &topology {
+                    assert!(!peers.contains(node), "{:?} {}", overlay, node);
+                    for peer in peers {
+                        assert!(topology[peer].contains(node), "{:?} {}", overlay, node);
+                    }
+                }
+                let (_, reached) = eccentricity(&topology, &nodes[0]);
+                assert_eq!(reached, n, "{:?} with {} nodes", overlay, n);
+            }
+        }
+    }
+
+    #[test]
+    fn overlays_do_not_depend_on_the_order_of_nodes() {
+        let mut shuffled = node_ids(16);
+        shuffled.reverse();
+        shuffled.swap(3, 11);
+        for overlay in OVERLAYS {
+            assert_eq!(overlay.build(&shuffled), overlay.build(&node_ids(16)));
+        }
+    }
+
+    #[test]
+    fn lines_and_rings() {
+        let nodes = node_ids(5);
+        let line = Overlay::Line.build(&nodes);
+        assert_eq!(degrees(&line, &nodes), [1, 2, 2, 2, 1]);
+        assert_eq!(eccentricity(&line, &nodes[0]).0, 4);
+        let ring = Overlay::Ring.build(&nodes);
+        assert_eq!(degrees(&ring, &nodes), [2; 5]);
+        assert_eq!(ring[&nodes[0]], [nodes[1].to_owned(), nodes[4].to_owned()]);
+    }
+
+    #[test]
+    fn grids_are_laid_out_row_by_row() {
+        let nodes = node_ids(9);
+        let grid = Overlay::Grid.build(&nodes);
+        assert_eq!(degrees(&grid, &nodes), [2, 3, 2, 3, 4, 3, 2, 3, 2]);
+        let center = ["n01", "n03", "n05", "n07"].map(String::from);
+        assert_eq!(grid["n04"], center);
+
+        // an incomplete last row
+        let nodes = node_ids(10);
+        let grid = Overlay::Grid.build(&nodes);
+        assert_eq!(grid["n08"], ["n04", "n09"].map(String::from));
+        assert_eq!(grid["n09"], ["n05", "n08"].map(String::from));
+        assert!(degrees(&grid, &nodes).iter().all(|d| (1..=4).contains(d)));
+    }
+
+    #[test]
+    fn trees_have_up_to_k_children() {
+        for k in [1, 2, 3, 5] {
+            let nodes = node_ids(25);
+            let tree = Overlay::Tree(k).build(&nodes);
+            let edges: usize = degrees(&tree, &nodes).iter().sum::<usize>() / 2;
+            assert_eq!(edges, nodes.len() - 1, "k = {}", k);
+            assert_eq!(tree[&nodes[0]].len(), k);
+            // a parent and up to k children
+            assert!(degrees(&tree, &nodes).iter().all(|d| *d <= k + 1));
+        }
+        // a depth of 2 with 13 nodes and k = 3, so a diameter of 4
+        let nodes = node_ids(13);
+        let tree = Overlay::Tree(3).build(&nodes);
+        assert_eq!(eccentricity(&tree, &nodes[0]).0, 2);
+        assert_eq!(eccentricity(&tree, &nodes[12]).0, 4);
+    }
+
+    #[test]
+    fn stars_link_every_node_to_a_hub() {
+        let nodes = node_ids(20);
+        let stars = Overlay::Stars(4).build(&nodes);
+        for (i, node) in nodes.iter().enumerate() {
+            if i < 4 {
+                // the other hubs, and 4 leaves each
+                assert_eq!(stars[node].len(), 3 + 4, "{}", node);
+            } else {
+                assert_eq!(stars[node], [nodes[i % 4].to_owned()]);
+            }
+            assert!(eccentricity(&stars, node).0 <= 3);
+        }
+        // more hubs than nodes makes a complete graph
+        let nodes = node_ids(3);
+        let stars = Overlay::Stars(5).build(&nodes);
+        assert_eq!(degrees(&stars, &nodes), [2, 2, 2]);
+    }
+
+    #[test]
+    fn expanders_have_up_to_degree_neighbours() {
+        for degree in [2, 4, 6] {
+            let nodes = node_ids(30);
+            let expander = Overlay::Expander(degree).build(&nodes);
+            for node in &nodes {
+                let links = expander[node].len();
+                assert!((2..=degree).contains(&links), "{} has {}", node, links);
+            }
+        }
+        // with a degree of 4, removing any single node leaves the others connected
+        let nodes = node_ids(30);
+        let expander = Overlay::Expander(4).build(&nodes);
+        for cut in &nodes {
+            let mut rest = expander.to_owned();
+            rest.remove(cut);
+            rest.values_mut()
+                .for_each(|peers| peers.retain(|p| p != cut));
+            let start = nodes.iter().find(|n| *n != cut).unwrap();
+            assert_eq!(eccentricity(&rest, start).1, nodes.len() - 1, "{}", cut);
+        }
+    }
+
+    #[test]
+    fn overlays_are_parsed_from_their_names() {
+        assert_eq!("line".parse::<Overlay>().unwrap(), Overlay::Line);
+        assert_eq!("ring".parse::<Overlay>().unwrap(), Overlay::Ring);
+        assert_eq!("grid".parse::<Overlay>().unwrap(), Overlay::Grid);
+        assert_eq!("tree:4".parse::<Overlay>().unwrap(), Overlay::Tree(4));
+        assert_eq!(
+            "expander:6".parse::<Overlay>().unwrap(),
+            Overlay::Expander(6)
+        );
+        assert_eq!("stars:3".parse::<Overlay>().unwrap(), Overlay::Stars(3));
+        let invalid = [
+            "",
+            "mesh",
+            "Line",
+            "line:2",
+            "tree",
+            "tree:",
+            "tree:0",
+            "tree:-1",
+            "tree:x",
+            "expander:1",
+            "stars:0",
+            "stars:2:3",
+        ];
+        for name in invalid {
+            assert!(name.parse::<Overlay>().is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Micro-operations of the transactional workloads.
+//!
+//! A `txn` request carries a list of micro-ops, each a JSON array `[function, key, value]`.
+//! The reply sends the same list back, with the values of reads filled in.
+use serde::{
+    de::{self, DeserializeOwned, Deserializer},
+    Deserialize, Serialize, Serializer,
+};
+use serde_json::Value;
+
+/// Micro-op of the `txn-rw-register` workload: `["r", k, null]` or `["w", k, v]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MicroOp<K, V> {
+    /// Read of a register. `value` is `None` in requests, and in replies when nothing was written.
+    Read { key: K, value: Option<V> },
+    Write { key: K, value: V },
+}
+
+impl<K, V> MicroOp<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            MicroOp::Read { key, .. } | MicroOp::Write { key, .. } => key,
+        }
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for MicroOp<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MicroOp::Read { key, value } => ("r", key, value).serialize(serializer),
+            MicroOp::Write { key, value } => ("w", key, value).serialize(serializer),
+        }
+    }
+}
+
+impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for MicroOp<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (function, key, value) = <(String, K, Option<V>)>::deserialize(deserializer)?;
+        match (function.as_str(), value) {
+            ("r", value) => Ok(MicroOp::Read { key, value }),
+            ("w", Some(value)) => Ok(MicroOp::Write { key, value }),
+            ("w", None) => Err(de::Error::custom("write without a value")),
+            (function, _) => Err(de::Error::unknown_variant(function, &["r", "w"])),
+        }
+    }
+}
+
+/// Micro-op of the `txn-list-append` workload: `["r", k, null]` or `["append", k, v]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListOp<K, E> {
+    /// Read of a list. `value` is `None` in requests, and in replies when nothing was appended.
+    Read { key: K, value: Option<Vec<E>> },
+    Append { key: K, element: E },
+}
+
+impl<K, E> ListOp<K, E> {
+    pub fn key(&self) -> &K {
+        match self {
+            ListOp::Read { key, .. } | ListOp::Append { key, .. } => key,
+        }
+    }
+}
+
+impl<K: Serialize, E: Serialize> Serialize for ListOp<K, E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ListOp::Read { key, value } => ("r", key, value).serialize(serializer),
+            ListOp::Append { key, element } => ("append", key, element).serialize(serializer),
+        }
+    }
+}
+
+/// The last slot holds a list for reads and an element for appends, so it is parsed once the function is known
+impl<'de, K: Deserialize<'de>, E: DeserializeOwned> Deserialize<'de> for ListOp<K, E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (function, key, value) = <(String, K, Value)>::deserialize(deserializer)?;
+        match function.as_str() {
+            "r" => Ok(ListOp::Read {
+                key,
+                value: serde_json::from_value(value).map_err(de::Error::custom)?,
+            }),
+            "append" => Ok(ListOp::Append {
+                key,
+                element: serde_json::from_value(value).map_err(de::Error::custom)?,
+            }),
+            function => Err(de::Error::unknown_variant(function, &["r", "append"])),
+        }
+    }
+}